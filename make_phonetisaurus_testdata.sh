@@ -0,0 +1,19 @@
+#!/bin/sh
+# Small Phonetisaurus model and its 1-best hypotheses on the test
+# sample, written to testdata/p11s for tests/phonetisaurus.rs.  Run
+# this with Phonetisaurus and OpenGrm installed and commit model.fst
+# and test.hyp to enable that test.
+
+TMPDIR=p11s
+OUTDIR=testdata/p11s
+mkdir -p $TMPDIR $OUTDIR
+phonetisaurus-align --iter=10 --input=testdata/librispeech.train.sample \
+                    --ofile=$TMPDIR/train.aligned --seq1_del=false --seq2_del=true \
+                    --seq1_max=2 --seq2_max=2 --grow=false
+ngramsymbols $TMPDIR/train.aligned $TMPDIR/train.syms
+farcompilestrings --symbols=$TMPDIR/train.syms --keep_symbols $TMPDIR/train.aligned \
+    | ngramcount --order=3 | ngrammake --method=kneser_ney - $TMPDIR/small.mod
+ngramprint -ARPA $TMPDIR/small.mod $TMPDIR/small.arpa
+phonetisaurus-arpa2wfst --lm=$TMPDIR/small.arpa --ofile=$OUTDIR/model.fst
+cut -d' ' -f1 testdata/librispeech.test.sample > $TMPDIR/test.words
+phonetisaurus-g2pfst --model=$OUTDIR/model.fst --wordlist=$TMPDIR/test.words > $OUTDIR/test.hyp
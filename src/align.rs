@@ -1,7 +1,7 @@
 use crate::{LogVectorFst, StdVectorFst};
 use anyhow::{anyhow, Result};
-use rustfst::algorithms::shortest_path;
 use rustfst::algorithms::weight_converters::SimpleWeightConverter;
+use rustfst::algorithms::{shortest_path, shortest_path_with_config, ShortestPathConfig};
 use rustfst::prelude::*;
 use rustfst::semirings::DivideType::DivideAny;
 use rustfst::utils::decode_linear_fst;
@@ -14,7 +14,7 @@ use std::io::BufReader;
 use std::path::PathBuf;
 
 /// Configuration parameters for the aligner
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Config {
     /// Maximum length of an input multi-token
     pub seq1_max: u8,
@@ -40,8 +40,19 @@ pub struct Config {
     pub s1_char_delim: String,
     /// Sequence two input separator
     pub s2_char_delim: String,
+    /// Penalize multi-token alignments (other than deletions and
+    /// insertions) in proportion to their length
+    pub penalize: bool,
+    /// Number of threads to use for the E-step
+    pub jobs: usize,
 }
 
+/// Alignment of an entry, as pairs of input and output clusters
+pub type Alignment<'a> = Vec<(&'a str, &'a str)>;
+
+/// Number of entries given to each thread at once in the E-step
+const BLOCK_SIZE: usize = 256;
+
 /// Grapheme to phoneme aligner
 #[derive(Debug)]
 pub struct Aligner {
@@ -50,6 +61,9 @@ pub struct Aligner {
     fsas: Vec<LogVectorFst>,
     alignment_model: HashMap<Label, LogWeight>,
     prev_alignment_model: HashMap<Label, LogWeight>,
+    /// Length of the longest side of each alignment without a skip,
+    /// for `penalize`
+    lengths: HashMap<Label, usize>,
     total: LogWeight,
     prev_total: LogWeight,
 }
@@ -80,6 +94,7 @@ impl Aligner {
             fsas,
             alignment_model,
             prev_alignment_model,
+            lengths: HashMap::new(),
             total,
             prev_total,
         }
@@ -88,7 +103,14 @@ impl Aligner {
     pub fn load_dictionary(&mut self, input: &PathBuf) -> Result<()> {
         let fh = File::open(input)?;
         let reader = BufReader::new(fh);
-        for spam in reader.lines().flatten() {
+        let lines = reader.lines().collect::<Result<Vec<String>, _>>()?;
+        self.load_entries(&lines)
+    }
+
+    /// Initialize alignment from lines of a pronunciation dictionary
+    /// in text format
+    pub fn load_entries(&mut self, lines: &[String]) -> Result<()> {
+        for spam in lines {
             let fields: Vec<&str> = spam.trim().split('\t').filter(|s| !s.is_empty()).collect();
             if fields.len() != 2 {
                 return Err(anyhow!(
@@ -156,6 +178,7 @@ impl Aligner {
                         }
                         let isymname = s1.to_owned() + s1s2_sep + s2;
                         let isym = self.isyms.add_symbol(&isymname);
+                        self.lengths.insert(isym, ik.max(jl));
                         let ostate: StateId =
                             ((i + ik) * (seq2.len() + 1) + (j + jl)).try_into().unwrap();
                         let tr = Tr::<LogWeight>::new(isym, isym, LogWeight::one(), ostate);
@@ -196,31 +219,58 @@ impl Aligner {
         Ok(())
     }
 
-    /// E-step of EM alignment
-    pub fn expectation(&mut self) -> Result<()> {
-        for fsa in &self.fsas {
-            let alpha = shortest_distance(fsa, false)?;
-            let beta = shortest_distance(fsa, true)?;
-            for q in fsa.states_iter() {
-                for arc in fsa.get_trs(q)?.trs() {
-                    let gamma = alpha[q as usize]
-                        .times(&arc.weight)?
-                        .times(&beta[arc.nextstate as usize])?
-                        .divide(&beta[0], DivideAny)?;
-                    if !gamma.value().is_nan() {
-                        // Update prev_alignment_model which will be
-                        // used to calculate the M-step below
-                        let weight = self
-                            .prev_alignment_model
-                            .entry(arc.ilabel)
-                            .or_insert_with(LogWeight::zero);
-                        weight.plus_assign(gamma)?;
-                        self.total.plus_assign(gamma)?;
-                    }
+    /// Symbol table for alignment lattices
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.isyms
+    }
+
+    /// Alignment lattices for each dictionary entry, weighted by the
+    /// current alignment model
+    pub fn lattices(&self) -> &[LogVectorFst] {
+        &self.fsas
+    }
+
+    /// E-step of EM alignment, returning the log-likelihood of the
+    /// dictionary under the current model
+    ///
+    /// Posteriors are computed in parallel if `jobs` is more than
+    /// one, but are always accumulated in the same order, so that
+    /// the results do not depend on the number of threads.
+    pub fn expectation(&mut self) -> Result<f64> {
+        let mut log_likelihood = 0.0;
+        let jobs = self.config.jobs.max(1);
+        for block in self.fsas.chunks(BLOCK_SIZE * jobs) {
+            let posteriors = if jobs == 1 {
+                vec![posteriors(block)]
+            } else {
+                let size = block.len().div_ceil(jobs);
+                std::thread::scope(|scope| {
+                    let threads: Vec<_> = block
+                        .chunks(size)
+                        .map(|chunk| scope.spawn(|| posteriors(chunk)))
+                        .collect();
+                    threads
+                        .into_iter()
+                        .map(|thread| thread.join().expect("E-step thread panicked"))
+                        .collect()
+                })
+            };
+            for chunk in posteriors {
+                let (likelihoods, posteriors) = chunk?;
+                log_likelihood += likelihoods.iter().sum::<f64>();
+                for (label, gamma) in posteriors {
+                    // Update prev_alignment_model which will be
+                    // used to calculate the M-step below
+                    let weight = self
+                        .prev_alignment_model
+                        .entry(label)
+                        .or_insert_with(LogWeight::zero);
+                    weight.plus_assign(gamma)?;
+                    self.total.plus_assign(gamma)?;
                 }
             }
         }
-        Ok(())
+        Ok(log_likelihood)
     }
 
     /// M-step of EM alignment
@@ -241,9 +291,15 @@ impl Aligner {
                 // The mutable arc iteration API in rustfst is not great
                 let mut trs = fsa.tr_iter_mut(q)?;
                 for idx in 0..trs.len() {
-                    // Avoid penalize_em for the moment
                     let label = trs[idx].ilabel;
-                    let weight = self.alignment_model[&label];
+                    let mut weight = self.alignment_model[&label];
+                    // Raise the probability to the power of the
+                    // length, leaving deletions and insertions alone
+                    if self.config.penalize {
+                        if let Some(&length) = self.lengths.get(&label) {
+                            weight = LogWeight::new(weight.value() * length as f32);
+                        }
+                    }
                     trs.set_weight(idx, weight)?;
                 }
             }
@@ -252,26 +308,427 @@ impl Aligner {
         Ok(change)
     }
 
-    /// Print alignments found to standard output
-    pub fn print_alignments(&self) -> Result<()> {
+    /// Run EM for at most `iter` iterations, or until the
+    /// log-likelihood improves by less than `tolerance`, printing
+    /// progress to standard error.  Returns the number of iterations
+    /// run.
+    pub fn em(&mut self, iter: usize, tolerance: Option<f64>) -> Result<usize> {
+        eprintln!("Starting EM...");
+        self.maximization()?;
+        let mut iterations = iter;
+        let mut prev_log_likelihood = None;
+        for i in 1..=iter {
+            let log_likelihood = self.expectation()?;
+            let delta = self.maximization()?;
+            eprintln!(
+                "Iteration: {} Log-likelihood: {} Change: {}",
+                i, log_likelihood, delta
+            );
+            if let (Some(tolerance), Some(prev)) = (tolerance, prev_log_likelihood) {
+                if log_likelihood - prev < tolerance {
+                    eprintln!("Converged after {} iterations", i);
+                    iterations = i;
+                    break;
+                }
+            }
+            prev_log_likelihood = Some(log_likelihood);
+        }
+        let log_likelihood = self.expectation()?;
+        let delta = self.maximization()?;
+        eprintln!(
+            "Last iteration: Log-likelihood: {} Change: {}",
+            log_likelihood, delta
+        );
+        Ok(iterations)
+    }
+
+    /// Symbols for the labels on a path
+    fn path_symbols(&self, labels: &[Label]) -> Result<Vec<&str>> {
+        // Handling undefined symbols with map/filter is much too hard
+        let mut syms = Vec::<&str>::new();
+        for &label in labels {
+            match self.isyms.get_symbol(label) {
+                None => return Err(anyhow!("Undefined label {}", label)),
+                Some(sym) => syms.push(sym),
+            }
+        }
+        Ok(syms)
+    }
+
+    /// Split the symbols on a path into input and output clusters
+    fn path_pairs(&self, labels: &[Label]) -> Result<Alignment<'_>> {
+        self.path_symbols(labels)?
+            .into_iter()
+            .map(|sym| {
+                sym.split_once(&self.config.s1s2_sep)
+                    .ok_or_else(|| anyhow!("Symbol {} is not an input-output pair", sym))
+            })
+            .collect()
+    }
+
+    /// Best path through the alignment lattice for an entry
+    fn best_path(&self, fsa: &LogVectorFst) -> Result<Vec<Label>> {
+        let mut mapper = SimpleWeightConverter {};
+        // Do not do any N-Best, forward-backward pruning, or any
+        // other such nonsense which the Phonetisaurus code admits
+        // is not very useful
+        let tfsa: StdVectorFst = weight_convert(fsa, &mut mapper)?;
+        let tfsa: StdVectorFst = shortest_path(&tfsa)?;
+        Ok(decode_linear_fst(&tfsa)?.ilabels)
+    }
+
+    /// Find the `nbest` best alignments for an entry along with their
+    /// posterior probabilities, dropping any whose posterior is below
+    /// `threshold`.  Unlike Phonetisaurus, the lattice is not pruned
+    /// by forward-backward before extracting the n-best paths: the
+    /// threshold only filters them afterwards, so it can give fewer
+    /// than `nbest` alignments but never different ones.
+    fn nbest_paths(
+        &self,
+        fsa: &LogVectorFst,
+        nbest: usize,
+        threshold: f32,
+    ) -> Result<Vec<(Vec<Label>, f32)>> {
+        let total = *shortest_distance(fsa, true)?[0].value();
         let mut mapper = SimpleWeightConverter {};
+        let tfsa: StdVectorFst = weight_convert(fsa, &mut mapper)?;
+        let config = ShortestPathConfig::default().with_nshortest(nbest);
+        let tfsa: StdVectorFst = shortest_path_with_config(&tfsa, config)?;
+        let mut paths: Vec<(Vec<Label>, f32)> = tfsa
+            .paths_iter()
+            .map(|path| (path.ilabels, (total - *path.weight.value()).exp()))
+            .filter(|&(_, posterior)| posterior >= threshold)
+            .collect();
+        paths.sort_by(|(l1, p1), (l2, p2)| p2.total_cmp(p1).then_with(|| l1.cmp(l2)));
+        Ok(paths)
+    }
+
+    /// Best alignment for each entry, as pairs of input and output
+    /// clusters
+    pub fn alignments(&self) -> Result<Vec<Alignment<'_>>> {
+        self.fsas
+            .iter()
+            .map(|fsa| self.path_pairs(&self.best_path(fsa)?))
+            .collect()
+    }
+
+    /// The `nbest` best alignments for each entry, as pairs of input
+    /// and output clusters, with their posterior probabilities.
+    /// Alignments with posterior probability below `threshold` (as
+    /// found by forward-backward) are then left out, as a filter on
+    /// the n-best list rather than pruning of the lattice.
+    pub fn nbest_alignments(
+        &self,
+        nbest: usize,
+        threshold: Option<f32>,
+    ) -> Result<Vec<Vec<(Alignment<'_>, f32)>>> {
+        let mut alignments = Vec::with_capacity(self.fsas.len());
         for fsa in &self.fsas {
-            // Do not do any N-Best, forward-backward pruning, or any
-            // other such nonsense which the Phonetisaurus code admits
-            // is not very useful
-            let tfsa: StdVectorFst = weight_convert(fsa, &mut mapper)?;
-            let tfsa: StdVectorFst = shortest_path(&tfsa)?;
-            let path = decode_linear_fst(&tfsa)?;
-            // Handling undefined symbols with map/filter is much too hard
-            let mut syms = Vec::<&str>::new();
-            for label in path.ilabels {
-                match self.isyms.get_symbol(label) {
-                    None => return Err(anyhow!("Undefined label {}", label)),
-                    Some(sym) => syms.push(sym),
-                }
+            let mut entry = Vec::with_capacity(nbest);
+            for (labels, posterior) in self.nbest_paths(fsa, nbest, threshold.unwrap_or(0.0))? {
+                entry.push((self.path_pairs(&labels)?, posterior));
             }
-            println!("{}", syms.join(" "));
+            alignments.push(entry);
+        }
+        Ok(alignments)
+    }
+
+    /// Write the best alignment for each entry, one per line
+    pub fn write_alignments<W: Write>(&self, mut writer: W) -> Result<()> {
+        for fsa in &self.fsas {
+            let syms = self.path_symbols(&self.best_path(fsa)?)?;
+            writeln!(writer, "{}", syms.join(" "))?;
+        }
+        Ok(())
+    }
+
+    /// Write the `nbest` best alignments for each entry, one per
+    /// line, each followed by its posterior probability (after a
+    /// TAB, in exponential notation so that small ones are kept),
+    /// with an empty line after each entry.  Alignments with
+    /// posterior probability below `threshold` (as found by
+    /// forward-backward) are then left out, as with
+    /// `nbest_alignments`.
+    pub fn write_nbest_alignments<W: Write>(
+        &self,
+        mut writer: W,
+        nbest: usize,
+        threshold: Option<f32>,
+    ) -> Result<()> {
+        for fsa in &self.fsas {
+            for (labels, posterior) in self.nbest_paths(fsa, nbest, threshold.unwrap_or(0.0))? {
+                let syms = self.path_symbols(&labels)?;
+                writeln!(writer, "{}\t{:e}", syms.join(" "), posterior)?;
+            }
+            writeln!(writer)?;
         }
         Ok(())
     }
+
+    /// Print alignments found to standard output
+    pub fn print_alignments(&self) -> Result<()> {
+        self.write_alignments(std::io::stdout().lock())
+    }
+
+    /// Print the `nbest` best alignments for each entry to standard
+    /// output, as with `write_nbest_alignments`
+    pub fn print_nbest_alignments(&self, nbest: usize, threshold: Option<f32>) -> Result<()> {
+        self.write_nbest_alignments(std::io::stdout().lock(), nbest, threshold)
+    }
+}
+
+/// Log-likelihoods of some alignment lattices, and posterior
+/// probabilities of all of their arcs, in order
+type Posteriors = (Vec<f64>, Vec<(Label, LogWeight)>);
+
+fn posteriors(fsas: &[LogVectorFst]) -> Result<Posteriors> {
+    let mut likelihoods = Vec::<f64>::with_capacity(fsas.len());
+    let mut posteriors = Vec::<(Label, LogWeight)>::new();
+    for fsa in fsas {
+        let alpha = shortest_distance(fsa, false)?;
+        let beta = shortest_distance(fsa, true)?;
+        likelihoods.push(-*beta[0].value() as f64);
+        for q in fsa.states_iter() {
+            for arc in fsa.get_trs(q)?.trs() {
+                let gamma = alpha[q as usize]
+                    .times(arc.weight)?
+                    .times(beta[arc.nextstate as usize])?
+                    .divide(&beta[0], DivideAny)?;
+                if !gamma.value().is_nan() {
+                    posteriors.push((arc.ilabel, gamma));
+                }
+            }
+        }
+    }
+    Ok((likelihoods, posteriors))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::train::ngram::{Config as NGramConfig, NGram};
+
+    fn config(jobs: usize) -> Config {
+        Config {
+            seq1_max: 2,
+            seq2_max: 2,
+            seq1_del: true,
+            seq2_del: true,
+            restrict: true,
+            seq1_sep: "|".to_owned(),
+            seq2_sep: "|".to_owned(),
+            s1s2_sep: "}".to_owned(),
+            eps: "<eps>".to_owned(),
+            skip: "_".to_owned(),
+            s1_char_delim: String::new(),
+            s2_char_delim: " ".to_owned(),
+            penalize: false,
+            jobs,
+        }
+    }
+
+    fn testdata(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(name)
+    }
+
+    fn small_aligner(config: Config) -> Aligner {
+        let dict = std::fs::read_to_string(testdata("testdata/librispeech.train.sample")).unwrap();
+        let lines: Vec<String> = dict.lines().take(50).map(str::to_owned).collect();
+        let mut aligner = Aligner::new(config);
+        aligner.load_entries(&lines).unwrap();
+        aligner
+    }
+
+    /// The E-step gives exactly the same model with several threads,
+    /// over enough entries for several blocks
+    #[test]
+    fn parallel_jobs() {
+        let dict = std::fs::read_to_string(testdata("testdata/librispeech.train.sample")).unwrap();
+        let lines: Vec<String> = dict
+            .lines()
+            .take(BLOCK_SIZE * 4 + 100)
+            .map(str::to_owned)
+            .collect();
+        let align = |jobs| {
+            let mut aligner = Aligner::new(config(jobs));
+            aligner.load_entries(&lines).unwrap();
+            aligner.em(1, None).unwrap();
+            aligner
+        };
+        let (serial, parallel) = (align(1), align(4));
+        assert_eq!(serial.fsas.len(), BLOCK_SIZE * 4 + 100);
+        assert_eq!(parallel.alignment_model, serial.alignment_model);
+        assert_eq!(parallel.alignments().unwrap(), serial.alignments().unwrap());
+    }
+
+    #[test]
+    fn tolerance() {
+        // Log-likelihoods as reported by em
+        let mut aligner = small_aligner(config(1));
+        aligner.maximization().unwrap();
+        let mut log_likelihoods = Vec::new();
+        for _ in 0..10 {
+            log_likelihoods.push(aligner.expectation().unwrap());
+            aligner.maximization().unwrap();
+        }
+        // EM never makes the likelihood worse
+        for pair in log_likelihoods.windows(2) {
+            assert!(pair[1] >= pair[0] - 1e-6, "{:?}", log_likelihoods);
+        }
+        let em = |iter, tolerance| small_aligner(config(1)).em(iter, tolerance).unwrap();
+        assert_eq!(em(10, None), 10);
+        // Needs two iterations to see a change
+        assert_eq!(em(10, Some(f64::INFINITY)), 2);
+        // Stops at the first iteration that improves by less
+        let change = log_likelihoods[4] - log_likelihoods[3];
+        let expected = log_likelihoods
+            .windows(2)
+            .position(|pair| pair[1] - pair[0] < change + 1e-9)
+            .unwrap()
+            + 2;
+        assert!(expected <= 5);
+        assert_eq!(em(10, Some(change + 1e-9)), expected);
+    }
+
+    /// Arc weights are the probabilities of the alignment model raised
+    /// to the power of the length, only with `penalize`, and never
+    /// for deletions or insertions
+    #[test]
+    fn penalize() {
+        for penalize in [false, true] {
+            let mut aligner = small_aligner(Config {
+                penalize,
+                ..config(1)
+            });
+            aligner.em(1, None).unwrap();
+            let (mut multi_token, mut multi_skip) = (0, 0);
+            for fsa in &aligner.fsas {
+                for q in fsa.states_iter() {
+                    for arc in fsa.get_trs(q).unwrap().trs() {
+                        let estimate = *aligner.alignment_model[&arc.ilabel].value();
+                        let sym = aligner.isyms.get_symbol(arc.ilabel).unwrap();
+                        let (s1, s2) = sym.split_once('}').unwrap();
+                        let length = s1.split('|').count().max(s2.split('|').count());
+                        let expected = if s1 == "_" || s2 == "_" {
+                            if length > 1 {
+                                multi_skip += 1;
+                            }
+                            estimate
+                        } else if penalize {
+                            if length > 1 {
+                                multi_token += 1;
+                            }
+                            estimate * length as f32
+                        } else {
+                            estimate
+                        };
+                        assert_eq!(*arc.weight.value(), expected, "Weight of {}", sym);
+                    }
+                }
+            }
+            assert!(multi_skip > 0);
+            assert!(!penalize || multi_token > 0);
+        }
+    }
+
+    /// An aligner where AB can be aligned as A|B}EY with probability
+    /// 1/2, or A}EY B}_ or A}_ B}EY with probability 1/4 each
+    fn nbest_aligner() -> Aligner {
+        let lines: Vec<String> = ["AB\tEY", "A\tEY", "B\tB"]
+            .iter()
+            .map(|&line| line.to_owned())
+            .collect();
+        let mut aligner = Aligner::new(Config {
+            seq1_del: false,
+            ..config(1)
+        });
+        aligner.load_entries(&lines).unwrap();
+        for fsa in aligner.fsas.iter_mut() {
+            for q in fsa.states_range() {
+                let mut trs = fsa.tr_iter_mut(q).unwrap();
+                for idx in 0..trs.len() {
+                    trs.set_weight(idx, LogWeight::new(2f32.ln())).unwrap();
+                }
+            }
+        }
+        aligner
+    }
+
+    #[test]
+    fn nbest_output() {
+        let aligner = nbest_aligner();
+        let nbest = aligner.nbest_alignments(3, None).unwrap();
+        assert_eq!(nbest.len(), 3);
+        for entry in &nbest {
+            let mass: f32 = entry.iter().map(|&(_, posterior)| posterior).sum();
+            assert!(mass <= 1.0 + 1e-6, "{:?}", entry);
+        }
+        assert_eq!(nbest[0][0], (vec![("A|B", "EY")], 0.5));
+        let write = |nbest, threshold| {
+            let mut output = Vec::new();
+            aligner
+                .write_nbest_alignments(&mut output, nbest, threshold)
+                .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        assert_eq!(write(1, None), "A|B}EY\t5e-1\n\nA}EY\t1e0\n\nB}B\t1e0\n\n");
+        // Ties are in the order of the labels
+        assert_eq!(
+            write(3, None),
+            "A|B}EY\t5e-1\nA}_ B}EY\t2.5e-1\nA}EY B}_\t2.5e-1\n\nA}EY\t1e0\n\nB}B\t1e0\n\n"
+        );
+        // The threshold filters the n-best list
+        assert_eq!(
+            write(3, Some(0.3)),
+            "A|B}EY\t5e-1\n\nA}EY\t1e0\n\nB}B\t1e0\n\n"
+        );
+        let mut output = Vec::new();
+        aligner.write_alignments(&mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "A|B}EY\nA}EY\nB}B\n");
+        // Each entry reads back as one lattice
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), write(3, None)).unwrap();
+        let mut ngram = NGram::new(NGramConfig {
+            fractional: true,
+            ..NGramConfig::default()
+        });
+        ngram.load_alignments(&file.path().to_path_buf()).unwrap();
+        assert_eq!(ngram.lattices.len(), 3);
+        assert_eq!(ngram.lattices[0].paths_iter().count(), 3);
+    }
+
+    /// Posteriors read back exactly as fractional counts
+    #[test]
+    fn nbest_round_trip() {
+        let mut aligner = small_aligner(config(1));
+        aligner.em(3, None).unwrap();
+        let nbest = aligner.nbest_alignments(3, None).unwrap();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        aligner
+            .write_nbest_alignments(File::create(file.path()).unwrap(), 3, None)
+            .unwrap();
+        let mut ngram = NGram::new(NGramConfig {
+            fractional: true,
+            ..NGramConfig::default()
+        });
+        ngram.load_alignments(&file.path().to_path_buf()).unwrap();
+        assert_eq!(ngram.lattices.len(), nbest.len());
+        let mut fractional = 0;
+        for (lattice, entry) in ngram.lattices.iter().zip(&nbest) {
+            // Each alignment is weighted by its posterior on its
+            // first arc
+            let start = lattice.start().unwrap();
+            let weights: Vec<f32> = lattice
+                .get_trs(start)
+                .unwrap()
+                .trs()
+                .iter()
+                .map(|tr| *tr.weight.value())
+                .collect();
+            let expected: Vec<f32> = entry.iter().map(|&(_, p)| -p.ln()).collect();
+            assert_eq!(weights, expected);
+            fractional += entry.iter().filter(|&&(_, p)| p < 0.01).count();
+        }
+        assert!(fractional > 0);
+    }
 }
@@ -0,0 +1,218 @@
+use super::{Config, Entries, ErrorCounts, Evaluator};
+use crate::align::{Aligner, Config as AlignerConfig};
+use crate::g2p::{Config as G2PConfig, G2P};
+use crate::model::{Metadata, Model};
+use crate::train::ngram::Config as NGramConfig;
+use crate::train::train_aligned;
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+
+/// How the held-out words are decoded and scored in
+/// cross-validation
+#[derive(Debug, Clone)]
+pub struct TestConfig {
+    /// Configuration for the g2p.  The separators default to those
+    /// the models were trained with.
+    pub g2p: G2PConfig,
+    /// Number of hypotheses to generate for each word
+    pub nbest: usize,
+    /// Number of threads to use for decoding
+    pub jobs: usize,
+    /// Configuration for scoring the hypotheses
+    pub evaluate: Config,
+}
+
+/// Assign each line of a dictionary to a fold, keeping all the
+/// pronunciations of a word in the same one.  Words are dealt out to
+/// the folds in turn, so that each covers the whole (usually sorted)
+/// dictionary.
+fn assign_folds(lines: &[String], folds: usize) -> Vec<usize> {
+    let mut assignments = Vec::with_capacity(lines.len());
+    let mut words = HashMap::new();
+    for line in lines {
+        let word = line.split('\t').next().unwrap_or_default();
+        let nwords = words.len();
+        let fold = *words.entry(word).or_insert(nwords % folds);
+        assignments.push(fold);
+    }
+    assignments
+}
+
+/// Parse the lines of a held-out fold into references, in the same
+/// format that `Aligner::load_entries` reads.  All the pronunciations
+/// of a word are grouped together, even if their lines are not.
+fn read_entries(lines: &[&String], s2_char_delim: &str) -> Result<Entries> {
+    let mut entries = Entries::new();
+    let mut index = HashMap::<&str, usize>::new();
+    for spam in lines {
+        let fields: Vec<&str> = spam.trim().split('\t').filter(|s| !s.is_empty()).collect();
+        if fields.len() != 2 {
+            return Err(anyhow!(
+                "Malformed line (must separate in/out with TAB): {}",
+                spam
+            ));
+        }
+        let pron = fields[1]
+            .split(s2_char_delim)
+            .filter(|s| !s.is_empty())
+            .map(str::to_owned)
+            .collect();
+        match index.get(fields[0]) {
+            Some(&i) => entries[i].1.push(pron),
+            None => {
+                index.insert(fields[0], entries.len());
+                entries.push((fields[0].to_owned(), vec![pron]));
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Evaluate training parameters by K-fold cross-validation on the
+/// lines of a pronunciation dictionary.  For each fold, the other
+/// folds are aligned once with `align_config`, then a model is
+/// trained for each of `ngram_configs` and used to decode the
+/// held-out words as given by `test_config`.  Returns the error
+/// counts for each N-Gram configuration and each fold.
+pub fn cross_validate(
+    lines: &[String],
+    folds: usize,
+    align_config: &AlignerConfig,
+    iter: usize,
+    tolerance: Option<f64>,
+    ngram_configs: &[NGramConfig],
+    test_config: &TestConfig,
+) -> Result<Vec<Vec<ErrorCounts>>> {
+    if folds < 2 {
+        return Err(anyhow!("Need at least 2 folds for cross-validation"));
+    }
+    let assignments = assign_folds(lines, folds);
+    if !assignments.contains(&(folds - 1)) {
+        return Err(anyhow!("Fewer words than folds in dictionary"));
+    }
+    let mut results = vec![Vec::with_capacity(folds); ngram_configs.len()];
+    for fold in 0..folds {
+        let (test, train): (Vec<_>, Vec<_>) = lines
+            .iter()
+            .zip(&assignments)
+            .partition(|&(_, &assignment)| assignment == fold);
+        let train: Vec<String> = train.into_iter().map(|(line, _)| line.clone()).collect();
+        let test: Vec<&String> = test.into_iter().map(|(line, _)| line).collect();
+        let refs = read_entries(&test, &align_config.s2_char_delim)?;
+        let mut aligner = Aligner::new(align_config.clone());
+        aligner.load_entries(&train)?;
+        aligner.em(iter, tolerance)?;
+        for (ngram_config, counts) in ngram_configs.iter().zip(results.iter_mut()) {
+            let model = Model {
+                metadata: Some(Metadata::from_configs(align_config, ngram_config)),
+                fst: train_aligned(&aligner, ngram_config.clone())?,
+            };
+            let g2p = G2P::new(test_config.g2p.clone(), model)?;
+            let words: Vec<&str> = refs.iter().map(|(word, _)| word.as_str()).collect();
+            let results = g2p.g2p_batch(&words, test_config.nbest, test_config.jobs);
+            let mut evaluator = Evaluator::new(test_config.evaluate.clone());
+            for ((_, prons), result) in refs.iter().zip(results) {
+                // Words that cannot be decoded count as errors, with
+                // an empty hypothesis
+                let hyps: Vec<Vec<String>> = result
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|(phones, _)| phones.into_iter().map(str::to_owned).collect())
+                    .collect();
+                evaluator.add(&hyps, prons);
+            }
+            counts.push(evaluator.counts());
+        }
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::g2p::OovPolicy;
+
+    #[test]
+    fn folds_keep_words_together() {
+        let lines: Vec<String> = [
+            "A\tEY", "A\tAH", "B\tB IY", "C\tS IY", "C\tK", "D\tD IY", "A\tAA", "E\tIY",
+        ]
+        .iter()
+        .map(|&line| line.to_owned())
+        .collect();
+        let assignments = assign_folds(&lines, 3);
+        assert_eq!(assignments, vec![0, 0, 1, 2, 2, 0, 0, 1]);
+        let test: Vec<&String> = lines
+            .iter()
+            .zip(&assignments)
+            .filter(|&(_, &fold)| fold == 0)
+            .map(|(line, _)| line)
+            .collect();
+        let entries = read_entries(&test, " ").unwrap();
+        let words: Vec<&str> = entries.iter().map(|(word, _)| word.as_str()).collect();
+        assert_eq!(words, ["A", "D"]);
+        assert_eq!(entries[0].1, [["EY"], ["AH"], ["AA"]]);
+    }
+
+    /// Held-out words are decoded as configured, so more hypotheses
+    /// can only lower the error rates
+    #[test]
+    fn nbest_hypotheses() {
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/testdata/librispeech.train.sample"
+        );
+        let dict = std::fs::read_to_string(path).unwrap();
+        let lines: Vec<String> = dict.lines().take(100).map(str::to_owned).collect();
+        let align_config = AlignerConfig {
+            seq1_max: 2,
+            seq2_max: 2,
+            seq1_del: false,
+            seq2_del: true,
+            restrict: true,
+            seq1_sep: "|".to_owned(),
+            seq2_sep: "|".to_owned(),
+            s1s2_sep: "}".to_owned(),
+            eps: "<eps>".to_owned(),
+            skip: "_".to_owned(),
+            s1_char_delim: String::new(),
+            s2_char_delim: " ".to_owned(),
+            penalize: false,
+            jobs: 1,
+        };
+        let ngram_configs = [NGramConfig {
+            order: 3,
+            ..Default::default()
+        }];
+        let counts = |nbest, jobs| {
+            let test_config = TestConfig {
+                g2p: G2PConfig {
+                    oov: OovPolicy::Empty,
+                    ..Default::default()
+                },
+                nbest,
+                jobs,
+                evaluate: Config::default(),
+            };
+            cross_validate(
+                &lines,
+                2,
+                &align_config,
+                3,
+                None,
+                &ngram_configs,
+                &test_config,
+            )
+            .unwrap()
+            .remove(0)
+        };
+        let (best, nbest) = (counts(1, 1), counts(4, 2));
+        for (best, nbest) in best.iter().zip(&nbest) {
+            assert_eq!(best.sequences, nbest.sequences);
+            assert!(nbest.sequence_errors <= best.sequence_errors);
+        }
+        let errors =
+            |counts: &[ErrorCounts]| -> usize { counts.iter().map(|c| c.sequence_errors).sum() };
+        assert!(errors(&nbest) < errors(&best));
+    }
+}
@@ -0,0 +1,445 @@
+mod cross_validation;
+
+pub use self::cross_validation::{cross_validate, TestConfig};
+use anyhow::{anyhow, Result};
+pub use regex::Regex;
+use std::fmt;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::ops::AddAssign;
+use std::path::PathBuf;
+
+/// Configuration parameters for evaluation, as in Phonetisaurus'
+/// `calculateER.py`
+#[derive(Debug, Default, Clone)]
+pub struct Config {
+    /// Tokens to ignore in hypotheses
+    pub ignore: Vec<String>,
+    /// Regular expression to remove from tokens in hypotheses
+    pub regex_ignore: Option<Regex>,
+    /// Also apply `ignore` and `regex_ignore` to references
+    pub ignore_both: bool,
+}
+
+/// Counts of edit operations and sequence errors
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ErrorCounts {
+    /// Total tokens in references
+    pub tokens: usize,
+    pub matches: usize,
+    pub substitutions: usize,
+    pub insertions: usize,
+    pub deletions: usize,
+    /// Number of sequences (i.e. words)
+    pub sequences: usize,
+    /// Number of sequences with any errors
+    pub sequence_errors: usize,
+}
+
+impl ErrorCounts {
+    /// Token (phone) error rate, (S+I+D)/T
+    pub fn token_error_rate(&self) -> f64 {
+        let errors = self.substitutions + self.insertions + self.deletions;
+        errors as f64 / self.tokens.max(1) as f64
+    }
+
+    /// Sequence (word) error rate
+    pub fn sequence_error_rate(&self) -> f64 {
+        self.sequence_errors as f64 / self.sequences.max(1) as f64
+    }
+}
+
+impl AddAssign for ErrorCounts {
+    fn add_assign(&mut self, other: ErrorCounts) {
+        self.tokens += other.tokens;
+        self.matches += other.matches;
+        self.substitutions += other.substitutions;
+        self.insertions += other.insertions;
+        self.deletions += other.deletions;
+        self.sequences += other.sequences;
+        self.sequence_errors += other.sequence_errors;
+    }
+}
+
+/// Same format as `calculateER.py`
+impl fmt::Display for ErrorCounts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ter = self.token_error_rate();
+        let ser = self.sequence_error_rate();
+        writeln!(f, "    (T)otal tokens in reference: {}", self.tokens)?;
+        writeln!(
+            f,
+            "    (M)atches: {}  (S)ubstitutions: {}  (I)nsertions: {}  (D)eletions: {}",
+            self.matches, self.substitutions, self.insertions, self.deletions
+        )?;
+        let correct = self.matches as f64 / self.tokens.max(1) as f64;
+        writeln!(
+            f,
+            "    % Correct (M/T)           -- %{:.2}",
+            100.0 * correct
+        )?;
+        writeln!(f, "    % Token ER ((S+I+D)/T)    -- %{:.2}", 100.0 * ter)?;
+        writeln!(
+            f,
+            "    % Accuracy 1.0-ER         -- %{:.2}",
+            100.0 * (1.0 - ter)
+        )?;
+        writeln!(f)?;
+        writeln!(
+            f,
+            "    (S)equences: {}  (C)orrect sequences: {}  (E)rror sequences: {}",
+            self.sequences,
+            self.sequences - self.sequence_errors,
+            self.sequence_errors
+        )?;
+        writeln!(f, "    % Sequence ER (E/S)       -- %{:.2}", 100.0 * ser)?;
+        write!(
+            f,
+            "    % Sequence Acc (1.0-E/S)  -- %{:.2}",
+            100.0 * (1.0 - ser)
+        )
+    }
+}
+
+/// Edit operation in the alignment of a hypothesis with a reference
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Edit {
+    Match(String),
+    /// Hypothesis and reference tokens
+    Substitution(String, String),
+    /// Hypothesis token
+    Insertion(String),
+    /// Reference token
+    Deletion(String),
+}
+
+/// Alignment of a hypothesis with a reference
+#[derive(Debug, Clone)]
+pub struct Alignment {
+    pub edits: Vec<Edit>,
+    pub counts: ErrorCounts,
+}
+
+/// Same format as `calculateER.py`, with the reference on top
+impl fmt::Display for Alignment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut lines = [Vec::new(), Vec::new(), Vec::new()];
+        for edit in &self.edits {
+            let (reference, conn, hyp) = match edit {
+                Edit::Match(token) => (token.as_str(), "|", token.as_str()),
+                Edit::Substitution(hyp, reference) => (reference.as_str(), " ", hyp.as_str()),
+                Edit::Insertion(hyp) => ("*", " ", hyp.as_str()),
+                Edit::Deletion(reference) => (reference.as_str(), " ", "-"),
+            };
+            let width = [reference, conn, hyp]
+                .iter()
+                .map(|s| s.chars().count())
+                .max()
+                .unwrap_or(0);
+            for (line, unit) in lines.iter_mut().zip([reference, conn, hyp]) {
+                line.push(center(unit, width));
+            }
+        }
+        for line in &lines {
+            writeln!(f, "{}", line.join(" "))?;
+        }
+        write!(f, "{}", self.counts)
+    }
+}
+
+/// Center a string like Python's `str.center`
+fn center(s: &str, width: usize) -> String {
+    let len = s.chars().count();
+    let pad = width.saturating_sub(len);
+    // Python puts the extra space on the left when the width is odd
+    let left = pad / 2 + (pad & width & 1);
+    format!("{}{}{}", " ".repeat(left), s, " ".repeat(pad - left))
+}
+
+/// Align a hypothesis with a reference using Levenshtein distance,
+/// preferring matches, then substitutions, insertions and deletions
+/// when tracing back, as `calculateER.py` does
+pub fn align(hyp: &[String], reference: &[String]) -> Alignment {
+    let (n, m) = (hyp.len(), reference.len());
+    let mut matrix = vec![vec![0usize; m + 1]; n + 1];
+    for (i, row) in matrix.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in matrix[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=n {
+        for j in 1..=m {
+            matrix[i][j] = if hyp[i - 1] == reference[j - 1] {
+                matrix[i - 1][j - 1]
+            } else {
+                1 + matrix[i - 1][j]
+                    .min(matrix[i][j - 1])
+                    .min(matrix[i - 1][j - 1])
+            };
+        }
+    }
+    let mut counts = ErrorCounts {
+        tokens: m,
+        sequences: 1,
+        sequence_errors: (matrix[n][m] > 0) as usize,
+        ..Default::default()
+    };
+    let mut edits = Vec::with_capacity(n.max(m));
+    let (mut i, mut j) = (n, m);
+    while i > 0 || j > 0 {
+        if i > 0 && j > 0 && hyp[i - 1] == reference[j - 1] {
+            edits.push(Edit::Match(hyp[i - 1].clone()));
+            counts.matches += 1;
+            i -= 1;
+            j -= 1;
+        } else if i > 0 && j > 0 && matrix[i][j] == matrix[i - 1][j - 1] + 1 {
+            edits.push(Edit::Substitution(
+                hyp[i - 1].clone(),
+                reference[j - 1].clone(),
+            ));
+            counts.substitutions += 1;
+            i -= 1;
+            j -= 1;
+        } else if i > 0 && (j == 0 || matrix[i][j] == matrix[i - 1][j] + 1) {
+            edits.push(Edit::Insertion(hyp[i - 1].clone()));
+            counts.insertions += 1;
+            i -= 1;
+        } else {
+            edits.push(Edit::Deletion(reference[j - 1].clone()));
+            counts.deletions += 1;
+            j -= 1;
+        }
+    }
+    edits.reverse();
+    Alignment { edits, counts }
+}
+
+/// Words with one or more pronunciations, in order
+pub type Entries = Vec<(String, Vec<Vec<String>>)>;
+
+/// Add a pronunciation to a list of entries, grouping consecutive
+/// ones for the same word
+fn add_entry(entries: &mut Entries, word: &str, pron: Vec<String>) {
+    match entries.last_mut() {
+        Some((last, prons)) if last == word => prons.push(pron),
+        _ => entries.push((word.to_owned(), vec![pron])),
+    }
+}
+
+/// Read a reference dictionary, with the word and its pronunciation
+/// separated by whitespace on each line.  Consecutive lines for the
+/// same word are taken as alternative pronunciations.
+pub fn read_references(input: &PathBuf) -> Result<Entries> {
+    let fh = File::open(input)?;
+    let reader = BufReader::new(fh);
+    let mut entries = Entries::new();
+    for spam in reader.lines() {
+        let spam = spam?;
+        let mut fields = spam.split_whitespace();
+        if let Some(word) = fields.next() {
+            add_entry(&mut entries, word, fields.map(str::to_owned).collect());
+        }
+    }
+    Ok(entries)
+}
+
+/// Read hypotheses as written by the `g2p` command, with the word,
+/// optionally the score, and the pronunciation separated by TAB on
+/// each line.  Consecutive lines for the same word are taken as
+/// N-best hypotheses.
+pub fn read_hypotheses(input: &PathBuf) -> Result<Entries> {
+    let fh = File::open(input)?;
+    let reader = BufReader::new(fh);
+    let mut entries = Entries::new();
+    for spam in reader.lines() {
+        let spam = spam?;
+        let fields: Vec<&str> = spam.trim_end_matches(['\r', '\n']).split('\t').collect();
+        let (word, pron) = match fields.as_slice() {
+            [word, _, pron] | [word, pron] => (word.trim(), pron),
+            _ => return Err(anyhow!("Malformed line in hypotheses: {}", spam)),
+        };
+        let pron = pron.split_whitespace().map(str::to_owned).collect();
+        add_entry(&mut entries, word, pron);
+    }
+    Ok(entries)
+}
+
+/// Computes error rates for G2P hypotheses
+#[derive(Debug, Default)]
+pub struct Evaluator {
+    pub config: Config,
+    counts: ErrorCounts,
+}
+
+impl Evaluator {
+    pub fn new(config: Config) -> Evaluator {
+        Evaluator {
+            config,
+            counts: ErrorCounts::default(),
+        }
+    }
+
+    /// Remove ignored tokens from a pronunciation
+    fn filter(&self, pron: &[String]) -> Vec<String> {
+        pron.iter()
+            .map(|token| match &self.config.regex_ignore {
+                Some(regex) => regex.replace_all(token, "").into_owned(),
+                None => token.clone(),
+            })
+            .filter(|token| !token.is_empty() && !self.config.ignore.contains(token))
+            .collect()
+    }
+
+    /// Score the hypotheses for a word against its references,
+    /// taking the best matching pair, and return their alignment.
+    /// Words with no reference pronunciation are not scored.
+    /// Unlike `calculateER.py`, empty hypotheses are scored (as all
+    /// deletions).
+    pub fn add(&mut self, hyps: &[Vec<String>], refs: &[Vec<String>]) -> Option<Alignment> {
+        let hyps: Vec<Vec<String>> = hyps.iter().map(|hyp| self.filter(hyp)).collect();
+        let refs: Vec<Vec<String>> = if self.config.ignore_both {
+            refs.iter()
+                .map(|reference| self.filter(reference))
+                .collect()
+        } else {
+            refs.to_vec()
+        };
+        let empty = Vec::new();
+        let hyps = if hyps.is_empty() { vec![empty] } else { hyps };
+        // Keep the first of equally good alignments
+        let mut best: Option<(usize, Alignment)> = None;
+        for hyp in &hyps {
+            for reference in refs.iter().filter(|r| !r.is_empty()) {
+                let alignment = align(hyp, reference);
+                let c = &alignment.counts;
+                let distance = c.substitutions + c.insertions + c.deletions;
+                if best.as_ref().is_none_or(|(d, _)| distance < *d) {
+                    best = Some((distance, alignment));
+                }
+            }
+        }
+        let (_, alignment) = best?;
+        self.counts += alignment.counts;
+        Some(alignment)
+    }
+
+    /// Total counts for all words scored so far
+    pub fn counts(&self) -> ErrorCounts {
+        self.counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(s: &str) -> Vec<String> {
+        s.split_whitespace().map(str::to_owned).collect()
+    }
+
+    #[test]
+    fn center_like_python() {
+        // Values of str.center in Python 3
+        assert_eq!(center("a", 4), " a  ");
+        assert_eq!(center("ab", 5), "  ab ");
+        assert_eq!(center("abc", 4), "abc ");
+        assert_eq!(center("a", 2), "a ");
+        assert_eq!(center("ab", 3), " ab");
+        assert_eq!(center("\u{e9}", 3), " \u{e9} ");
+        assert_eq!(center("abc", 2), "abc");
+    }
+
+    #[test]
+    fn align_edits() {
+        let alignment = align(&tokens("TH IH NG K"), &tokens("S IH NG"));
+        assert_eq!(
+            alignment.edits,
+            vec![
+                Edit::Substitution("TH".to_owned(), "S".to_owned()),
+                Edit::Match("IH".to_owned()),
+                Edit::Match("NG".to_owned()),
+                Edit::Insertion("K".to_owned()),
+            ]
+        );
+        // Ties between edits are broken as calculateER.py does
+        let alignment = align(&tokens("AA"), &tokens("EY B IY"));
+        assert_eq!(
+            alignment.edits,
+            vec![
+                Edit::Deletion("EY".to_owned()),
+                Edit::Deletion("B".to_owned()),
+                Edit::Substitution("AA".to_owned(), "IY".to_owned()),
+            ]
+        );
+        let c = align(&tokens("D AO G"), &tokens("D AO G")).counts;
+        assert_eq!((c.tokens, c.matches, c.sequence_errors), (3, 3, 0));
+        let c = align(&[], &tokens("D AO G")).counts;
+        assert_eq!((c.deletions, c.sequence_errors), (3, 1));
+    }
+
+    /// Output of `calculateER.py --verbose` for the same words
+    #[test]
+    fn alignment_display() {
+        let alignment = align(&tokens("W AO T ER Z"), &tokens("W AA T ER"));
+        let expected = concat!(
+            "W AA T ER *\n",
+            "|    | |   \n",
+            "W AO T ER Z\n",
+            "    (T)otal tokens in reference: 4\n",
+            "    (M)atches: 3  (S)ubstitutions: 1  (I)nsertions: 1  (D)eletions: 0\n",
+            "    % Correct (M/T)           -- %75.00\n",
+            "    % Token ER ((S+I+D)/T)    -- %50.00\n",
+            "    % Accuracy 1.0-ER         -- %50.00\n",
+            "\n",
+            "    (S)equences: 1  (C)orrect sequences: 0  (E)rror sequences: 1\n",
+            "    % Sequence ER (E/S)       -- %100.00\n",
+            "    % Sequence Acc (1.0-E/S)  -- %0.00",
+        );
+        assert_eq!(alignment.to_string(), expected);
+        let alignment = align(&tokens("AA"), &tokens("EY B IY"));
+        let lines: Vec<String> = alignment.to_string().lines().map(str::to_owned).collect();
+        assert_eq!(lines[..3], ["EY B IY", "       ", "-  - AA"]);
+    }
+
+    /// Totals from `calculateER.py` over the same words, where the
+    /// first of equally good hypotheses is taken
+    #[test]
+    fn counts_display() {
+        let mut evaluator = Evaluator::default();
+        for (hyps, reference) in [
+            (vec!["K AE T"], "K AE T S"),
+            (vec!["D AO G"], "D AO G"),
+            (vec!["B AH T ER", "B AH D"], "B AH T"),
+            (vec!["W AO T ER Z"], "W AA T ER"),
+        ] {
+            let hyps: Vec<Vec<String>> = hyps.into_iter().map(tokens).collect();
+            evaluator.add(&hyps, &[tokens(reference)]).unwrap();
+        }
+        let expected = concat!(
+            "    (T)otal tokens in reference: 14\n",
+            "    (M)atches: 12  (S)ubstitutions: 1  (I)nsertions: 2  (D)eletions: 1\n",
+            "    % Correct (M/T)           -- %85.71\n",
+            "    % Token ER ((S+I+D)/T)    -- %28.57\n",
+            "    % Accuracy 1.0-ER         -- %71.43\n",
+            "\n",
+            "    (S)equences: 4  (C)orrect sequences: 1  (E)rror sequences: 3\n",
+            "    % Sequence ER (E/S)       -- %75.00\n",
+            "    % Sequence Acc (1.0-E/S)  -- %25.00",
+        );
+        assert_eq!(evaluator.counts().to_string(), expected);
+    }
+
+    #[test]
+    fn regex_ignore() {
+        let mut evaluator = Evaluator::new(Config {
+            regex_ignore: Some(Regex::new("[0-9]").unwrap()),
+            ..Default::default()
+        });
+        let alignment = evaluator
+            .add(&[tokens("K AE1 T")], &[tokens("K AE T")])
+            .unwrap();
+        assert_eq!(alignment.counts.matches, 3);
+    }
+}
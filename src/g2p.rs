@@ -1,23 +1,130 @@
+use crate::model::{Metadata, Model};
 use crate::StdVectorFst;
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use rustfst::algorithms::compose::compose;
+use rustfst::algorithms::determinize::{determinize_with_config, DeterminizeConfig};
+use rustfst::algorithms::rm_epsilon::rm_epsilon;
+use rustfst::algorithms::{
+    project, relabel_pairs, shortest_path_with_config, ProjectType, ShortestPathConfig,
+};
 use rustfst::prelude::*;
 use rustfst::utils::decode_linear_fst;
 use std::cmp::{max, min};
 use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Arc;
 
+/// What to do with graphemes not found in the model
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OovPolicy {
+    /// Fail with an error
+    Fail,
+    /// Ignore the grapheme
+    Skip,
+    /// Give the word an empty pronunciation with infinite score
+    Empty,
+}
+
+impl FromStr for OovPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<OovPolicy> {
+        match s {
+            "fail" => Ok(OovPolicy::Fail),
+            "skip" => Ok(OovPolicy::Skip),
+            "empty" => Ok(OovPolicy::Empty),
+            _ => Err(anyhow!("Unknown OOV policy {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for OovPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            OovPolicy::Fail => "fail",
+            OovPolicy::Skip => "skip",
+            OovPolicy::Empty => "empty",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Where the pronunciations of a word or phrase come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    /// The exception lexicon
+    Lexicon,
+    /// The model
+    Model,
+    /// The exception lexicon for some words of a phrase and the model
+    /// for others
+    Mixed,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Source::Lexicon => "lexicon",
+            Source::Model => "model",
+            Source::Mixed => "mixed",
+        };
+        write!(f, "{}", name)
+    }
+}
+
 /// Configuration parameters for the g2p
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Config {
-    /// Grapheme separator
-    pub gsep: String,
-    /// Phoneme skip marker
-    pub skip: String,
+    /// Grapheme separator, if not the one the model was trained with
+    pub gsep: Option<String>,
+    /// Phoneme skip marker, if not the one the model was trained with
+    pub skip: Option<String>,
+    /// Separator for input multi-tokens, if not the one the model was
+    /// trained with
+    pub seq1_sep: Option<String>,
+    /// Separator for output multi-tokens, if not the one the model
+    /// was trained with
+    pub seq2_sep: Option<String>,
     /// Write the output FSTs for debugging
     pub write_fsts: bool,
+    /// Maximum difference in score from the best pronunciation for
+    /// n-best output
+    pub beam: Option<f32>,
+    /// Stop n-best output once this much of the probability mass of
+    /// the n-best list is covered
+    pub pmass: Option<f32>,
+    /// What to do with graphemes not found in the model (after
+    /// substitution)
+    pub oov: OovPolicy,
+    /// Characters separating the words of a phrase, which are
+    /// pronounced separately and joined (none by default)
+    pub delimiters: String,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            gsep: None,
+            skip: None,
+            seq1_sep: None,
+            seq2_sep: None,
+            write_fsts: false,
+            beam: None,
+            pmass: None,
+            oov: OovPolicy::Fail,
+            delimiters: String::new(),
+        }
+    }
 }
 
+/// Pronunciations of a word or phrase with their scores
+pub type Prons<'a> = Vec<(Vec<&'a str>, f32)>;
+
 type ClusterMap = HashMap<Label, Vec<Label>>;
 type InvClusterMap = HashMap<Vec<Label>, Label>;
 
@@ -28,6 +135,12 @@ pub struct G2P {
     pub config: Config,
     /// Model (just a WFST actually)
     model: StdVectorFst,
+    /// Configuration the model was trained with, if known
+    metadata: Option<Metadata>,
+    /// Grapheme separator
+    gsep: String,
+    /// Phoneme skip marker
+    skip: String,
     /// Maximum size of input clusters
     imax: u8,
     /// Input symbol table
@@ -39,10 +152,49 @@ pub struct G2P {
     /// Mapping of output symbol clusters
     omap: ClusterMap,
     // Other mappings are not used!
+    /// Substitutions for graphemes not found in the model
+    substitutions: HashMap<String, String>,
+    /// Pronunciations of words and phrases, looked up before decoding
+    lexicon: HashMap<String, Vec<Vec<String>>>,
 }
 
+// A loaded model can be shared between threads
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<G2P>();
+};
+
 impl G2P {
-    pub fn new(config: Config, mut model: StdVectorFst) -> Result<G2P> {
+    /// Create a G2P from a model, using the separators it was
+    /// trained with unless they are given in `config`
+    pub fn new(config: Config, model: Model) -> Result<G2P> {
+        let Model {
+            metadata,
+            fst: mut model,
+        } = model;
+        let gsep = config
+            .gsep
+            .clone()
+            .or_else(|| metadata.as_ref().and_then(|m| m.gsep.clone()))
+            .unwrap_or_default();
+        let skip = config
+            .skip
+            .clone()
+            .or_else(|| metadata.as_ref().map(|m| m.skip.clone()))
+            .unwrap_or_else(|| "_".to_owned());
+        // Without metadata, the separators are found in the symbol
+        // tables themselves
+        let seq1_sep = config
+            .seq1_sep
+            .clone()
+            .or_else(|| metadata.as_ref().map(|m| m.seq1_sep.clone()));
+        let seq2_sep = config
+            .seq2_sep
+            .clone()
+            .or_else(|| metadata.as_ref().map(|m| m.seq2_sep.clone()));
+        if Some(&skip) == seq1_sep.as_ref() || Some(&skip) == seq2_sep.as_ref() {
+            return Err(anyhow!("Skip token {} is also a separator", skip));
+        }
         let isyms = Arc::clone(
             model
                 .input_symbols()
@@ -53,31 +205,129 @@ impl G2P {
                 .output_symbols()
                 .ok_or_else(|| anyhow!("No output symbol table"))?,
         );
+        Self::import_skip(&mut model, &isyms, &skip)?;
         tr_sort(&mut model, ILabelCompare {});
-        let (imax, _imap, inv_imap) = Self::load_clusters(&isyms)?;
-        let (_omax, omap, _inv_omap) = Self::load_clusters(&osyms)?;
+        let (imax, _imap, inv_imap) = Self::load_clusters(&isyms, seq1_sep.as_deref())
+            .context("Invalid input symbol table")?;
+        let (_omax, omap, _inv_omap) = Self::load_clusters(&osyms, seq2_sep.as_deref())
+            .context("Invalid output symbol table")?;
         Ok(G2P {
             config,
             model,
+            metadata,
+            gsep,
+            skip,
             isyms,
             osyms,
             imax,
             inv_imap,
             omap,
+            substitutions: HashMap::new(),
+            lexicon: HashMap::new(),
         })
     }
 
-    fn load_clusters(syms: &SymbolTable) -> Result<(u8, ClusterMap, InvClusterMap)> {
+    /// Models from `phonetisaurus-arpa2wfst` may have the skip token
+    /// as an input symbol on arcs that insert phones, where ours have
+    /// epsilon.  Nothing in a word can match it, so relabel those
+    /// arcs to epsilon.
+    fn import_skip(model: &mut StdVectorFst, isyms: &SymbolTable, skip: &str) -> Result<()> {
+        if let Some(label) = isyms.get_label(skip).filter(|&label| label != EPS_LABEL) {
+            relabel_pairs(model, vec![(label, EPS_LABEL)], vec![])?;
+        }
+        Ok(())
+    }
+
+    /// Configuration the model was trained with, if known
+    pub fn metadata(&self) -> Option<&Metadata> {
+        self.metadata.as_ref()
+    }
+
+    /// Read substitutions for unknown graphemes, one per line, with
+    /// the grapheme and its replacement (which may be several
+    /// graphemes, separated by `gsep`, or nothing) separated by TAB
+    pub fn load_substitutions(&mut self, input: &PathBuf) -> Result<()> {
+        let fh = File::open(input)?;
+        let reader = BufReader::new(fh);
+        for spam in reader.lines() {
+            let spam = spam?;
+            if spam.trim().is_empty() {
+                continue;
+            }
+            let (grapheme, replacement) = spam.split_once('\t').ok_or_else(|| {
+                anyhow!("Malformed line (must separate in/out with TAB): {}", spam)
+            })?;
+            self.substitutions
+                .insert(grapheme.trim().to_owned(), replacement.trim().to_owned());
+        }
+        Ok(())
+    }
+
+    /// Read an exception lexicon of words and phrases, in the same
+    /// format as dictionaries for training: one pronunciation per
+    /// line, with the graphemes (separated by `gsep`) and the phones
+    /// (separated by spaces) separated by TAB.  Several lines for the
+    /// same entry give alternative pronunciations, best first.
+    pub fn load_lexicon(&mut self, input: &PathBuf) -> Result<()> {
+        let fh = File::open(input)?;
+        let reader = BufReader::new(fh);
+        for spam in reader.lines() {
+            let spam = spam?;
+            if spam.trim().is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = spam.trim().split('\t').filter(|s| !s.is_empty()).collect();
+            if fields.len() != 2 {
+                return Err(anyhow!(
+                    "Malformed line (must separate in/out with TAB): {}",
+                    spam
+                ));
+            }
+            let pron = fields[1].split_whitespace().map(str::to_owned).collect();
+            self.lexicon
+                .entry(self.lexicon_key(fields[0]))
+                .or_default()
+                .push(pron);
+        }
+        Ok(())
+    }
+
+    /// Normalize the separators between graphemes in a lexicon entry
+    fn lexicon_key(&self, word: &str) -> String {
+        let graphemes: Vec<&str> = word.split(&self.gsep).filter(|s| !s.is_empty()).collect();
+        graphemes.join(&self.gsep)
+    }
+
+    /// Find the multi-token clusters in a symbol table, which are all
+    /// the symbols other than epsilon and the separator.  The
+    /// separator is found by name, or for models that do not say what
+    /// it is, at label 1 as Phonetisaurus puts it.
+    fn load_clusters(
+        syms: &SymbolTable,
+        tie: Option<&str>,
+    ) -> Result<(u8, ClusterMap, InvClusterMap)> {
         let mut clusters = ClusterMap::new();
         let mut invclusters = InvClusterMap::new();
-        let tie = syms
-            .get_symbol(1)
-            .ok_or_else(|| anyhow!("Cluster separator not found in symbol table"))?; // FIXME: stupid magic
+        let (tie_label, tie) = match tie {
+            Some(tie) => (
+                syms.get_label(tie)
+                    .ok_or_else(|| anyhow!("Separator {} not found in symbol table", tie))?,
+                tie,
+            ),
+            None => (
+                1,
+                syms.get_symbol(1)
+                    .ok_or_else(|| anyhow!("Separator not found in symbol table"))?,
+            ),
+        };
+        if tie_label == EPS_LABEL || tie.is_empty() {
+            return Err(anyhow!("Separator cannot be epsilon"));
+        }
         let mut maxlen = 1;
-        for i in 2..syms.len() as Label {
-            let sym = syms
-                .get_symbol(i)
-                .expect("Symbol table lies about its size");
+        for (label, sym) in syms.iter() {
+            if label == EPS_LABEL || label == tie_label {
+                continue;
+            }
             let cluster: Result<Vec<Label>, _> = sym
                 .split(tie)
                 .map(|s| {
@@ -87,8 +337,8 @@ impl G2P {
                 .collect();
             let cluster = cluster?;
             maxlen = max(maxlen, cluster.len());
-            clusters.insert(i, cluster.clone());
-            invclusters.insert(cluster, i);
+            clusters.insert(label, cluster.clone());
+            invclusters.insert(cluster, label);
         }
         let maxlen: u8 = maxlen.try_into()?;
         Ok((maxlen, clusters, invclusters))
@@ -96,7 +346,7 @@ impl G2P {
 
     fn entry_to_fsa(
         &self,
-        word: &Vec<Label>,
+        word: &[Label],
         maxlen: u8,
         invmap: &HashMap<Vec<Label>, Label>,
     ) -> Result<StdVectorFst> {
@@ -128,18 +378,38 @@ impl G2P {
         Ok(fsa)
     }
 
-    pub fn g2p(&self, word: &str) -> Result<(Vec<&str>, f32)> {
-        let maybe_syms: Result<Vec<Label>, _> = word
-            .split(&self.config.gsep)
-            .filter(|s| !s.is_empty())
-            .map(|s| {
-                self.isyms
-                    .get_label(s)
-                    .ok_or_else(|| anyhow!("Input symbol {} not found", s))
-            })
-            .collect();
+    /// Look up the labels for the graphemes in a word, substituting
+    /// or skipping unknown ones as configured.  Returns `None` if the
+    /// word should have an empty pronunciation.
+    fn labels(&self, word: &str) -> Result<Option<Vec<Label>>> {
+        let gsep = &self.gsep;
+        let mut labels = Vec::<Label>::new();
+        for s in word.split(gsep).filter(|s| !s.is_empty()) {
+            if let Some(label) = self.isyms.get_label(s) {
+                labels.push(label);
+                continue;
+            }
+            let replacement = match self.substitutions.get(s) {
+                Some(replacement) => replacement.split(gsep).filter(|s| !s.is_empty()).collect(),
+                None => vec![s],
+            };
+            for s in replacement {
+                match (self.isyms.get_label(s), self.config.oov) {
+                    (Some(label), _) => labels.push(label),
+                    (None, OovPolicy::Fail) => {
+                        return Err(anyhow!("Input symbol {} not found", s));
+                    }
+                    (None, OovPolicy::Skip) => (),
+                    (None, OovPolicy::Empty) => return Ok(None),
+                }
+            }
+        }
+        Ok(Some(labels))
+    }
 
-        let mut fst = self.entry_to_fsa(&maybe_syms?, self.imax, &self.inv_imap)?;
+    /// Build the lattice of pronunciations for a word
+    fn lattice(&self, word: &str, labels: &[Label]) -> Result<StdVectorFst> {
+        let mut fst = self.entry_to_fsa(labels, self.imax, &self.inv_imap)?;
         fst.set_input_symbols(Arc::clone(&self.isyms));
         fst.set_output_symbols(Arc::clone(&self.isyms));
         if self.config.write_fsts {
@@ -152,16 +422,16 @@ impl G2P {
         if self.config.write_fsts {
             fst.write(word.to_owned() + ".lat.fst")?;
         }
-        let fst: StdVectorFst = shortest_path(&fst)?;
-        if self.config.write_fsts {
-            fst.write(word.to_owned() + ".path.fst")?;
-        }
-        let path = decode_linear_fst(&fst)?;
+        Ok(fst)
+    }
+
+    /// Expand output clusters and remove skips
+    fn phones(&self, olabels: &[Label]) -> Result<Vec<&str>> {
         // In Lisp or Python, this would easy in functional style, in
         // Rust, because of the incomprehensible type inference... NO.
         let mut wtf_rustc = Vec::<&str>::new();
-        for label in path.olabels {
-            if let Some(cluster) = self.omap.get(&label) {
+        for label in olabels {
+            if let Some(cluster) = self.omap.get(label) {
                 for &l in cluster {
                     // We should never have unknown labels in a cluster!
                     let sym = self
@@ -170,17 +440,270 @@ impl G2P {
                         .expect("Cluster has unknown labels");
                     wtf_rustc.push(sym);
                 }
-            } else if let Some(sym) = self.osyms.get_symbol(label) {
+            } else if let Some(sym) = self.osyms.get_symbol(*label) {
                 wtf_rustc.push(sym);
             } else {
                 // On the other hand the model might contain unknown labels
                 return Err(anyhow!("Output label {} not found in model", label));
             }
         }
-        let phones = wtf_rustc
-            .into_iter()
-            .filter(|&s| s != self.config.skip)
+        Ok(wtf_rustc.into_iter().filter(|&s| s != self.skip).collect())
+    }
+
+    /// Pronunciations of a word or phrase in the exception lexicon,
+    /// best first, with a score of zero
+    fn lookup(&self, word: &str) -> Option<Vec<(Vec<&str>, f32)>> {
+        let prons = self.lexicon.get(&self.lexicon_key(word))?;
+        Some(
+            prons
+                .iter()
+                .map(|pron| (pron.iter().map(String::as_str).collect(), 0.0))
+                .collect(),
+        )
+    }
+
+    /// Split a phrase into the parts that are pronounced separately:
+    /// the whole phrase if it is in the exception lexicon or has
+    /// nothing but delimiters, otherwise its words, split on the
+    /// configured delimiters (which also drops any leading or
+    /// trailing ones from a single word)
+    fn parts<'a>(&self, phrase: &'a str) -> Vec<&'a str> {
+        if self.lookup(phrase).is_some() {
+            return vec![phrase];
+        }
+        let words: Vec<&str> = phrase
+            .split(|c| self.config.delimiters.contains(c))
+            .filter(|s| !s.is_empty())
             .collect();
-        Ok((phones, *path.weight.value()))
+        if words.is_empty() {
+            return vec![phrase];
+        }
+        words
+    }
+
+    /// Where the pronunciations of a phrase come from
+    pub fn source(&self, phrase: &str) -> Source {
+        let parts = self.parts(phrase);
+        let known = parts
+            .iter()
+            .filter(|&&part| self.lookup(part).is_some())
+            .count();
+        if known == parts.len() {
+            Source::Lexicon
+        } else if known == 0 {
+            Source::Model
+        } else {
+            Source::Mixed
+        }
+    }
+
+    /// Find the best pronunciation for a phrase.  Words and phrases
+    /// in the exception lexicon are returned as they are, otherwise
+    /// each word is decoded with the model.  Pronunciations of the
+    /// words are joined, adding up the scores.  If any word has no
+    /// pronunciation, neither does the phrase.
+    pub fn g2p(&self, phrase: &str) -> Result<(Vec<&str>, f32)> {
+        let mut pron = (Vec::new(), 0.0);
+        for word in self.parts(phrase) {
+            let (phones, score) = match self.lookup(word) {
+                Some(mut prons) => prons.swap_remove(0),
+                None => self.word_g2p(word)?,
+            };
+            if score.is_infinite() {
+                return Ok((Vec::new(), f32::INFINITY));
+            }
+            pron.0.extend(phones);
+            pron.1 += score;
+        }
+        Ok(pron)
+    }
+
+    /// Find the `n` best distinct pronunciations for a word or
+    /// phrase, best first, like `g2p`.  `beam` and `pmass` apply to
+    /// the n-best list of each word, whether it comes from the
+    /// exception lexicon or the model.  For phrases, these are
+    /// combined, then `beam` and `pmass` are applied to the result.
+    pub fn g2p_nbest(&self, phrase: &str, n: usize) -> Result<Vec<(Vec<&str>, f32)>> {
+        let nbest = |word| match self.lookup(word) {
+            Some(mut prons) => {
+                prons.truncate(n);
+                self.apply_cutoffs(&mut prons);
+                Ok(prons)
+            }
+            None => self.word_nbest(word, n),
+        };
+        let parts = self.parts(phrase);
+        if let [word] = parts.as_slice() {
+            return nbest(word);
+        }
+        let mut prons = vec![(Vec::new(), 0.0)];
+        for word in parts {
+            let word_prons = nbest(word)?;
+            if word_prons.iter().any(|(_, score)| score.is_infinite()) {
+                return Ok(vec![(Vec::new(), f32::INFINITY)]);
+            }
+            let mut combined = Vec::<(Vec<&str>, f32)>::with_capacity(prons.len() * n);
+            for (phones, score) in &prons {
+                for (word_phones, word_score) in &word_prons {
+                    let mut phones = phones.clone();
+                    phones.extend(word_phones);
+                    let score = score + word_score;
+                    match combined.iter_mut().find(|(p, _)| *p == phones) {
+                        Some((_, best)) => *best = best.min(score),
+                        None => combined.push((phones, score)),
+                    }
+                }
+            }
+            combined.sort_by(|(p1, s1), (p2, s2)| s1.total_cmp(s2).then_with(|| p1.cmp(p2)));
+            combined.truncate(n);
+            prons = combined;
+        }
+        self.apply_cutoffs(&mut prons);
+        Ok(prons)
+    }
+
+    /// Find the `n` best pronunciations for each of a list of words
+    /// or phrases, as with `g2p_nbest` (or `g2p` if `n` is 1), split
+    /// between `jobs` threads and returned in the same order
+    pub fn g2p_batch<S: AsRef<str> + Sync>(
+        &self,
+        phrases: &[S],
+        n: usize,
+        jobs: usize,
+    ) -> Vec<Result<Prons<'_>>> {
+        let decode = |phrase: &S| {
+            if n == 1 {
+                self.g2p(phrase.as_ref()).map(|pron| vec![pron])
+            } else {
+                self.g2p_nbest(phrase.as_ref(), n)
+            }
+        };
+        if jobs <= 1 {
+            return phrases.iter().map(decode).collect();
+        }
+        let size = phrases.len().div_ceil(jobs).max(1);
+        std::thread::scope(|scope| {
+            let threads: Vec<_> = phrases
+                .chunks(size)
+                .map(|chunk| scope.spawn(move || chunk.iter().map(decode).collect::<Vec<_>>()))
+                .collect();
+            threads
+                .into_iter()
+                .flat_map(|thread| thread.join().expect("G2P thread panicked"))
+                .collect()
+        })
+    }
+
+    /// Drop pronunciations outside `beam` or beyond `pmass`, if set
+    fn apply_cutoffs(&self, prons: &mut Vec<(Vec<&str>, f32)>) {
+        if let (Some(beam), Some(&(_, best))) = (self.config.beam, prons.first()) {
+            prons.retain(|&(_, score)| score - best <= beam);
+        }
+        if let Some(pmass) = self.config.pmass {
+            truncate_pmass(prons, pmass);
+        }
+    }
+
+    /// Find the best pronunciation for a single word
+    fn word_g2p(&self, word: &str) -> Result<(Vec<&str>, f32)> {
+        let fst = match self.labels(word)? {
+            Some(labels) => self.lattice(word, &labels)?,
+            None => return Ok((Vec::new(), f32::INFINITY)),
+        };
+        let fst: StdVectorFst = shortest_path(&fst)?;
+        if self.config.write_fsts {
+            fst.write(word.to_owned() + ".path.fst")?;
+        }
+        let path = decode_linear_fst(&fst)?;
+        Ok((self.phones(&path.olabels)?, *path.weight.value()))
+    }
+
+    /// Find the `n` best distinct pronunciations for a single word,
+    /// best first.  Alignments giving the same sequence of phones (after
+    /// expanding clusters and removing skips) are only counted once,
+    /// with the score of the best one.
+    ///
+    /// Fewer than `n` are returned if `beam` is set and the rest are
+    /// outside the beam, or if `pmass` is set and the ones before
+    /// them already cover that much of the probability mass of the
+    /// n-best list.
+    fn word_nbest(&self, word: &str, n: usize) -> Result<Vec<(Vec<&str>, f32)>> {
+        let mut fst = match self.labels(word)? {
+            Some(labels) => self.lattice(word, &labels)?,
+            None => return Ok(vec![(Vec::new(), f32::INFINITY)]),
+        };
+        // Keep only the best path for each output sequence (the
+        // default delta is coarse enough to change the scores)
+        project(&mut fst, ProjectType::ProjectOutput);
+        rm_epsilon(&mut fst)?;
+        let config = DeterminizeConfig::default().with_delta(1e-6);
+        let fst: StdVectorFst = determinize_with_config(&fst, config)?;
+        // Distinct output sequences can still give the same phones,
+        // so keep asking for more paths until there are enough.  Ties
+        // are broken by the phones, since the order in which
+        // shortest_path returns them is arbitrary, so this also means
+        // getting all the paths tied with the last one.
+        if n == 0 {
+            return Ok(Vec::new());
+        }
+        let mut nshortest = n;
+        loop {
+            let config = ShortestPathConfig::default().with_nshortest(nshortest);
+            let paths: StdVectorFst = shortest_path_with_config(&fst, config)?;
+            if self.config.write_fsts {
+                paths.write(word.to_owned() + ".nbest.fst")?;
+            }
+            let paths: Vec<FstPath<TropicalWeight>> = paths.paths_iter().collect();
+            let npaths = paths.len();
+            let mut worst = f32::NEG_INFINITY;
+            let mut prons = Vec::<(Vec<&str>, f32)>::with_capacity(npaths);
+            for path in paths {
+                let score = *path.weight.value();
+                worst = worst.max(score);
+                let phones = self.phones(&path.olabels)?;
+                match prons.iter_mut().find(|(p, _)| *p == phones) {
+                    Some((_, best)) => *best = best.min(score),
+                    None => prons.push((phones, score)),
+                }
+            }
+            prons.sort_by(|(p1, s1), (p2, s2)| s1.total_cmp(s2).then_with(|| p1.cmp(p2)));
+            // Any paths not returned score at least as badly as these
+            let mut done =
+                npaths < nshortest || prons.get(n - 1).is_some_and(|&(_, score)| score < worst);
+            if let (Some(beam), Some(&(_, best))) = (self.config.beam, prons.first()) {
+                prons.retain(|&(_, score)| score - best <= beam);
+                done = done || worst - best > beam;
+            }
+            if done {
+                prons.truncate(n);
+                if let Some(pmass) = self.config.pmass {
+                    truncate_pmass(&mut prons, pmass);
+                }
+                return Ok(prons);
+            }
+            nshortest *= 2;
+        }
+    }
+}
+
+/// Keep the best pronunciations until their share of the probability
+/// mass of all of them reaches `pmass`, as Phonetisaurus does.  The
+/// total over the whole lattice is not used, since with epsilon
+/// backoff transitions it counts the same N-Grams several times.
+fn truncate_pmass(prons: &mut Vec<(Vec<&str>, f32)>, pmass: f32) {
+    let best = match prons.first() {
+        Some(&(_, best)) => best,
+        None => return,
+    };
+    // Scale by the best one to avoid underflow
+    let probs: Vec<f32> = prons.iter().map(|&(_, s)| (best - s).exp()).collect();
+    let total: f32 = probs.iter().sum();
+    let mut mass = 0.0;
+    for (i, p) in probs.iter().enumerate() {
+        mass += p / total;
+        if mass >= pmass {
+            prons.truncate(i + 1);
+            return;
+        }
     }
 }
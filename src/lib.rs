@@ -2,7 +2,9 @@ use rustfst::fst_impls::VectorFst;
 use rustfst::semirings::{LogWeight, TropicalWeight};
 
 pub mod align;
+pub mod evaluate;
 pub mod g2p;
+pub mod model;
 pub mod train;
 
 type StdVectorFst = VectorFst<TropicalWeight>;
@@ -1,12 +1,18 @@
-use anyhow::Result;
-use clap::{Parser, Subcommand};
-use rustfst::prelude::*;
+use anyhow::{anyhow, Result};
+use clap::{Args, Parser, Subcommand};
 use rustfst_g2p::align::{Aligner, Config as AlignerConfig};
-use rustfst_g2p::g2p::{Config as G2PConfig, G2P};
+use rustfst_g2p::evaluate::{
+    self, Config as EvaluateConfig, Entries, ErrorCounts, Evaluator, Regex, TestConfig,
+};
+use rustfst_g2p::g2p::{Config as G2PConfig, OovPolicy, G2P};
+use rustfst_g2p::model::{hash_file, Metadata, Model};
 use rustfst_g2p::train::ngram::{Config as NGramConfig, NGram};
+use rustfst_g2p::train::smooth::Method;
+use rustfst_g2p::train::train_dictionary;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::prelude::*;
-use std::io::BufReader;
+use std::io::{BufReader, BufWriter};
 use std::path::PathBuf;
 
 #[derive(Parser)]
@@ -17,30 +23,137 @@ struct Cli {
     command: Commands,
 }
 
+/// Aligner options for every command that aligns a dictionary
+#[derive(Args)]
+struct AlignerArgs {
+    /// Maximum number of EM iterations to perform
+    #[arg(long, default_value_t = 11)]
+    iter: u8,
+    /// Stop EM when the log-likelihood improves by less than this
+    #[arg(long)]
+    tolerance: Option<f64>,
+    /// Restrict to N-1 and 1-M alignments
+    #[arg(long, action = clap::ArgAction::Set, default_value_t = true)]
+    restrict: bool,
+    /// Penalize multi-token alignments (other than deletions and
+    /// insertions) in proportion to their length
+    #[arg(long)]
+    penalize: bool,
+    /// Token used to separate input-output subsequences in the g2p model
+    #[arg(long, default_value = "}")]
+    s1s2_sep: String,
+    /// Epsilon symbol
+    #[arg(long, default_value = "<eps>")]
+    eps: String,
+    /// Sequence two input separator
+    #[arg(long, default_value = " ")]
+    s2_char_delim: String,
+}
+
+/// Options for aligning a dictionary with one set of parameters
+#[derive(Args)]
+struct AlignArgs {
+    #[command(flatten)]
+    aligner: AlignerArgs,
+    /// Maximum length of an input multi-token
+    #[arg(long, default_value_t = 2)]
+    seq1_max: u8,
+    /// Maximum length of an output multi-token
+    #[arg(long, default_value_t = 2)]
+    seq2_max: u8,
+    /// Allow deletion of input tokens
+    #[arg(long, action = clap::ArgAction::Set, default_value_t = true)]
+    seq1_del: bool,
+    /// Allow deletion of output tokens
+    #[arg(long, action = clap::ArgAction::Set, default_value_t = true)]
+    seq2_del: bool,
+    /// Multi-token separator for input tokens
+    #[arg(long, default_value = "|")]
+    seq1_sep: String,
+    /// Multi-token separator for output tokens
+    #[arg(long, default_value = "|")]
+    seq2_sep: String,
+    /// Skip token used to represent null transitions.  Distinct from epsilon
+    #[arg(long, default_value = "_")]
+    skip: String,
+    /// Sequence one input separator
+    #[arg(long, default_value = "")]
+    s1_char_delim: String,
+    /// Number of threads to use for the E-step
+    #[arg(long, default_value_t = 1)]
+    jobs: usize,
+}
+
+impl AlignArgs {
+    /// Configuration for the aligner
+    fn config(&self) -> AlignerConfig {
+        AlignerConfig {
+            restrict: self.aligner.restrict,
+            seq1_max: self.seq1_max,
+            seq2_max: self.seq2_max,
+            seq1_del: self.seq1_del,
+            seq2_del: self.seq2_del,
+            seq1_sep: self.seq1_sep.clone(),
+            seq2_sep: self.seq2_sep.clone(),
+            s1s2_sep: self.aligner.s1s2_sep.clone(),
+            eps: self.aligner.eps.clone(),
+            skip: self.skip.clone(),
+            s1_char_delim: self.s1_char_delim.clone(),
+            s2_char_delim: self.aligner.s2_char_delim.clone(),
+            penalize: self.aligner.penalize,
+            jobs: self.jobs,
+        }
+    }
+}
+
+/// N-Gram options for every command that trains on a dictionary
+#[derive(Args)]
+struct NGramArgs {
+    /// Smoothing method (kneser_ney, witten_bell, absolute, katz, unsmoothed)
+    #[arg(long, default_value_t = Method::KneserNey)]
+    method: Method,
+    /// Train on expected counts over all alignments (Kneser-Ney only)
+    #[arg(long)]
+    fractional: bool,
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Aligns a dictionary
     Align {
         /// Input dictionary file
         input: PathBuf,
-        /// Maximum length of an input multi-token
-        #[arg(long, default_value_t = 2)]
-        seq1_max: u8,
-        /// Maximum length of an output multi-token
-        #[arg(long, default_value_t = 2)]
-        seq2_max: u8,
-        /// Maximum number of EM iterations to perform
-        #[arg(long, default_value_t = 11)]
-        iter: u8,
-        /// Allow deletion of input tokens
-        #[arg(long, action = clap::ArgAction::Set, default_value_t = true)]
-        seq1_del: bool,
-        /// Allow deletion of output tokens
-        #[arg(long, action = clap::ArgAction::Set, default_value_t = true)]
-        seq2_del: bool,
-        /// Restrict to N-1 and 1-M alignments
-        #[arg(long, action = clap::ArgAction::Set, default_value_t = true)]
-        restrict: bool,
+        #[command(flatten)]
+        align: AlignArgs,
+        /// Number of alignments to output for each entry, with their
+        /// posterior probabilities
+        #[arg(long, default_value_t = 1)]
+        nbest: usize,
+        /// Do not output alignments with posterior probability below
+        /// this (applied to the n-best list after it is found, not by
+        /// pruning the lattice beforehand)
+        #[arg(long)]
+        pthresh: Option<f32>,
+        /// Write alignments to this file instead of standard output
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    Train {
+        /// Path to aligned dictionary
+        aligned: PathBuf,
+        /// Path to output model
+        output: PathBuf,
+        /// Order of N-Grams
+        #[arg(long, default_value_t = 5)]
+        order: u8,
+        /// Smoothing method (kneser_ney, witten_bell, absolute, katz, unsmoothed)
+        #[arg(long, default_value_t = Method::KneserNey)]
+        method: Method,
+        /// Train on expected counts over the n-best alignments of each
+        /// entry, which must have posteriors, as written by `align
+        /// --nbest` (Kneser-Ney only)
+        #[arg(long)]
+        fractional: bool,
         /// Multi-token separator for input tokens
         #[arg(long, default_value = "|")]
         seq1_sep: String,
@@ -50,113 +163,340 @@ enum Commands {
         /// Token used to separate input-output subsequences in the g2p model
         #[arg(long, default_value = "}")]
         s1s2_sep: String,
-        /// Epsilon symbol
-        #[arg(long, default_value = "<eps>")]
-        eps: String,
-        /// Skip token used to represent null transitions.  Distinct from epsilon
+        /// Skip token used to represent null transitions
         #[arg(long, default_value = "_")]
         skip: String,
-        /// Sequence one input separator
-        #[arg(long, default_value = "")]
-        s1_char_delim: String,
-        /// Sequence two input separator
-        #[arg(long, default_value = " ")]
-        s2_char_delim: String,
+        /// Also write the joint-sequence model in ARPA format
+        #[arg(long)]
+        arpa: Option<PathBuf>,
+        /// Write the output FSTs for debugging
+        #[arg(long)]
+        write_fsts: bool,
     },
-    Train {
-        /// Path to aligned dictionary
-        aligned: PathBuf,
+    /// Aligns a dictionary and trains a g2p model on it
+    TrainDict {
+        /// Input dictionary file
+        input: PathBuf,
         /// Path to output model
         output: PathBuf,
+        #[command(flatten)]
+        align: AlignArgs,
         /// Order of N-Grams
         #[arg(long, default_value_t = 5)]
         order: u8,
+        #[command(flatten)]
+        ngram: NGramArgs,
         /// Write the output FSTs for debugging
         #[arg(long)]
         write_fsts: bool,
     },
+    /// Converts a joint-sequence model in ARPA format to a g2p model
+    #[command(name = "arpa2fst")]
+    Arpa2Fst {
+        /// Path to ARPA model
+        arpa: PathBuf,
+        /// Path to output model
+        output: PathBuf,
+        /// Multi-token separator for input tokens
+        #[arg(long, default_value = "|")]
+        seq1_sep: String,
+        /// Multi-token separator for output tokens
+        #[arg(long, default_value = "|")]
+        seq2_sep: String,
+        /// Token used to separate input-output subsequences in the g2p model
+        #[arg(long, default_value = "}")]
+        s1s2_sep: String,
+        /// Skip token used to represent null transitions
+        #[arg(long, default_value = "_")]
+        skip: String,
+    },
     /// Performs grapheme-to-phoneme conversion on input
     G2P {
         /// Path to trained model
         model: PathBuf,
         /// Input word list
         input: PathBuf,
-        /// Grapheme separator
-        #[arg(long, default_value = "")]
-        gsep: String,
-        /// Phoneme skip marker
-        #[arg(long, default_value = "_")]
-        skip: String,
+        /// Grapheme separator (default: as trained, or none)
+        #[arg(long)]
+        gsep: Option<String>,
+        /// Phoneme skip marker (default: as trained, or _)
+        #[arg(long)]
+        skip: Option<String>,
+        /// Separator for input multi-tokens (default: as trained, or
+        /// the symbol at label 1)
+        #[arg(long)]
+        seq1_sep: Option<String>,
+        /// Separator for output multi-tokens (default: as trained, or
+        /// the symbol at label 1)
+        #[arg(long)]
+        seq2_sep: Option<String>,
         /// Write the output FSTs for debugging
         #[arg(long)]
         write_fsts: bool,
+        /// Number of distinct pronunciations to output for each word
+        #[arg(long, default_value_t = 1)]
+        nbest: usize,
+        /// Only output pronunciations within this score of the best one
+        #[arg(long)]
+        beam: Option<f32>,
+        /// Stop once the pronunciations output have this much of the
+        /// probability mass of the n-best list
+        #[arg(long)]
+        pmass: Option<f32>,
+        /// What to do with unknown graphemes (fail, skip, empty)
+        #[arg(long, default_value_t = OovPolicy::Fail)]
+        oov: OovPolicy,
+        /// Substitutions for unknown graphemes (TAB-separated)
+        #[arg(long)]
+        substitutions: Option<PathBuf>,
+        /// Characters separating words in input phrases, which are
+        /// pronounced separately (e.g. " -'")
+        #[arg(long, default_value = "")]
+        delimiters: String,
+        /// Exception lexicon of words and phrases with their
+        /// pronunciations, in the same format as training
+        /// dictionaries, used instead of the model for those it has
+        #[arg(long)]
+        lexicon: Option<PathBuf>,
+        /// Write words that failed, with the reason, to this file
+        /// instead of standard error
+        #[arg(long)]
+        errors: Option<PathBuf>,
+        /// Number of threads to use for decoding
+        #[arg(long, default_value_t = 1)]
+        jobs: usize,
         /// Print scores in output
         #[arg(long, action = clap::ArgAction::Set, default_value_t = true)]
         print_scores: bool,
         /// Default scores vals are negative logs
         #[arg(long, action = clap::ArgAction::Set, default_value_t = true)]
         nlog_probs: bool,
+        /// Print where pronunciations come from (lexicon, model or
+        /// mixed) in a last column
+        #[arg(long)]
+        print_source: bool,
+    },
+    /// Computes word and phone error rates against a reference dictionary
+    Evaluate {
+        /// Reference dictionary file
+        reference: PathBuf,
+        /// Hypotheses, as output by g2p
+        #[arg(
+            long,
+            required_unless_present_any = ["model", "folds"],
+            conflicts_with_all = ["model", "folds"]
+        )]
+        hyp: Option<PathBuf>,
+        /// Path to trained model to generate hypotheses with
+        #[arg(long, conflicts_with = "folds")]
+        model: Option<PathBuf>,
+        /// Cross-validate training parameters with this many folds of
+        /// the reference dictionary instead
+        #[arg(long)]
+        folds: Option<usize>,
+        /// Orders of N-Grams to try with --folds (comma-separated)
+        #[arg(long, value_delimiter = ',', default_value = "5")]
+        order: Vec<u8>,
+        /// Maximum lengths of input multi-tokens to try with --folds
+        #[arg(long, value_delimiter = ',', default_value = "2")]
+        seq1_max: Vec<u8>,
+        /// Maximum lengths of output multi-tokens to try with --folds
+        #[arg(long, value_delimiter = ',', default_value = "2")]
+        seq2_max: Vec<u8>,
+        /// Whether to allow deletion of input tokens with --folds
+        /// (true, false, or true,false)
+        #[arg(long, value_delimiter = ',', default_value = "true")]
+        seq1_del: Vec<bool>,
+        /// Whether to allow deletion of output tokens with --folds
+        /// (true, false, or true,false)
+        #[arg(long, value_delimiter = ',', default_value = "true")]
+        seq2_del: Vec<bool>,
+        #[command(flatten)]
+        aligner: Box<AlignerArgs>,
+        #[command(flatten)]
+        ngram: NGramArgs,
+        /// Grapheme separator (default: as trained, or none)
+        #[arg(long)]
+        gsep: Option<String>,
+        /// Phoneme skip marker (default: as trained, or _)
+        #[arg(long)]
+        skip: Option<String>,
+        /// Separator for input multi-tokens (default: as trained, or
+        /// the symbol at label 1)
+        #[arg(long)]
+        seq1_sep: Option<String>,
+        /// Separator for output multi-tokens (default: as trained, or
+        /// the symbol at label 1)
+        #[arg(long)]
+        seq2_sep: Option<String>,
+        /// Number of hypotheses to generate for each word
+        #[arg(long, default_value_t = 1)]
+        nbest: usize,
+        /// Only generate hypotheses within this score of the best one
+        #[arg(long)]
+        beam: Option<f32>,
+        /// Stop once the hypotheses generated have this much of the
+        /// probability mass of the n-best list
+        #[arg(long)]
+        pmass: Option<f32>,
+        /// Number of threads to use for decoding (and alignment with
+        /// --folds)
+        #[arg(long, default_value_t = 1)]
+        jobs: usize,
+        /// Tokens to ignore in hypotheses (space-separated)
+        #[arg(long, default_value = "")]
+        ignore: String,
+        /// Regular expression to remove from tokens in hypotheses
+        #[arg(long)]
+        regex_ignore: Option<Regex>,
+        /// Apply --ignore and --regex-ignore to references too
+        #[arg(long)]
+        ignore_both: bool,
+        /// Print the alignment and errors for each word
+        #[arg(long, conflicts_with = "folds")]
+        verbose: bool,
     },
 }
 
+/// Number of words given to each thread at once
+const BLOCK_SIZE: usize = 256;
+
+/// Print the error rates for each fold of a cross-validation and
+/// their means, returning the means as percentages
+fn report_folds(params: String, counts: &[ErrorCounts]) -> (String, f64, f64) {
+    println!("{}", params);
+    for (fold, counts) in counts.iter().enumerate() {
+        println!(
+            "    Fold {}: WER: {:.2}%  PER: {:.2}%",
+            fold + 1,
+            100.0 * counts.sequence_error_rate(),
+            100.0 * counts.token_error_rate()
+        );
+    }
+    let n = counts.len() as f64;
+    let wer = 100.0 * counts.iter().map(|c| c.sequence_error_rate()).sum::<f64>() / n;
+    let per = 100.0 * counts.iter().map(|c| c.token_error_rate()).sum::<f64>() / n;
+    println!("    Mean WER: {:.2}%  PER: {:.2}%", wer, per);
+    (params, wer, per)
+}
+
 fn main() -> Result<()> {
     let cli = Cli::parse();
     // This is super gross... is there a better way?!?
     match cli.command {
         Commands::Align {
             input,
-            iter,
-            restrict,
-            seq1_max,
-            seq2_max,
-            seq1_del,
-            seq2_del,
+            align,
+            nbest,
+            pthresh,
+            output,
+        } => {
+            let mut aligner = Aligner::new(align.config());
+            aligner.load_dictionary(&input)?;
+            aligner.em(align.aligner.iter as usize, align.aligner.tolerance)?;
+            let mut writer: Box<dyn Write> = match output {
+                Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+                None => Box::new(std::io::stdout().lock()),
+            };
+            if nbest == 1 && pthresh.is_none() {
+                aligner.write_alignments(&mut writer)?;
+            } else {
+                aligner.write_nbest_alignments(&mut writer, nbest, pthresh)?;
+            }
+            writer.flush()?;
+            Ok(())
+        }
+        Commands::Train {
+            aligned,
+            output,
+            order,
+            method,
+            fractional,
             seq1_sep,
             seq2_sep,
             s1s2_sep,
-            eps,
             skip,
-            s1_char_delim,
-            s2_char_delim,
+            arpa,
+            write_fsts,
         } => {
-            let mut aligner = Aligner::new(AlignerConfig {
-                restrict,
-                seq1_max,
-                seq2_max,
-                seq1_del,
-                seq2_del,
+            let mut trainer = NGram::new(NGramConfig {
+                order,
+                method,
+                fractional,
                 seq1_sep,
                 seq2_sep,
                 s1s2_sep,
-                eps,
                 skip,
-                s1_char_delim,
-                s2_char_delim,
+                write_fsts,
             });
-
-            aligner.load_dictionary(&input)?;
-            eprintln!("Starting EM...");
-            aligner.maximization()?;
-            for i in 1..=iter {
-                aligner.expectation()?;
-                let delta = aligner.maximization()?;
-                eprintln!("Iteration: {} Change: {}", i, delta);
+            trainer.load_alignments(&aligned)?;
+            let model = trainer.train_joint()?;
+            if let Some(arpa) = arpa {
+                trainer.write_arpa(&model, &arpa)?;
             }
-            aligner.expectation()?;
-            let delta = aligner.maximization()?;
-            eprintln!("Last iteration: {}", delta);
-            aligner.print_alignments()?;
+            let model = Model {
+                metadata: Some(Metadata {
+                    data_hash: Some(hash_file(&aligned)?),
+                    ..Metadata::from_ngram(&trainer.config)
+                }),
+                fst: trainer.make_g2p_model(&model)?,
+            };
+            model.write(&output)?;
             Ok(())
         }
-        Commands::Train {
-            aligned,
+        Commands::TrainDict {
+            input,
             output,
+            align,
             order,
+            ngram,
             write_fsts,
         } => {
-            let mut trainer = NGram::new(NGramConfig { order, write_fsts });
-            trainer.load_alignments(&aligned)?;
-            let model = trainer.train()?;
+            let align_config = align.config();
+            let ngram_config = NGramConfig {
+                order,
+                method: ngram.method,
+                fractional: ngram.fractional,
+                seq1_sep: align_config.seq1_sep.clone(),
+                seq2_sep: align_config.seq2_sep.clone(),
+                s1s2_sep: align_config.s1s2_sep.clone(),
+                skip: align_config.skip.clone(),
+                write_fsts,
+            };
+            let AlignerArgs {
+                iter, tolerance, ..
+            } = align.aligner;
+            let model =
+                train_dictionary(&input, align_config, iter as usize, tolerance, ngram_config)?;
+            model.write(&output)?;
+            Ok(())
+        }
+        Commands::Arpa2Fst {
+            arpa,
+            output,
+            seq1_sep,
+            seq2_sep,
+            s1s2_sep,
+            skip,
+        } => {
+            let mut trainer = NGram::new(NGramConfig {
+                seq1_sep,
+                seq2_sep,
+                s1s2_sep,
+                skip,
+                ..Default::default()
+            });
+            let model = trainer.read_arpa(&arpa)?;
+            // Only the separators are known
+            let model = Model {
+                metadata: Some(Metadata {
+                    order: None,
+                    method: None,
+                    fractional: None,
+                    ..Metadata::from_ngram(&trainer.config)
+                }),
+                fst: trainer.make_g2p_model(&model)?,
+            };
             model.write(&output)?;
             Ok(())
         }
@@ -165,35 +505,291 @@ fn main() -> Result<()> {
             input,
             gsep,
             skip,
+            seq1_sep,
+            seq2_sep,
             write_fsts,
+            nbest,
+            beam,
+            pmass,
+            oov,
+            substitutions,
+            delimiters,
+            lexicon,
+            errors,
+            jobs,
             print_scores,
             nlog_probs,
+            print_source,
         } => {
-            let model = VectorFst::<TropicalWeight>::read(&model)?;
-            let g2p = G2P::new(
+            let model = Model::read(&model)?;
+            let mut g2p = G2P::new(
                 G2PConfig {
                     gsep,
                     skip,
+                    seq1_sep,
+                    seq2_sep,
                     write_fsts,
+                    beam,
+                    pmass,
+                    oov,
+                    delimiters,
                 },
                 model,
             )?;
+            if let Some(substitutions) = substitutions {
+                g2p.load_substitutions(&substitutions)?;
+            }
+            if let Some(lexicon) = lexicon {
+                g2p.load_lexicon(&lexicon)?;
+            }
             let fh = File::open(input)?;
             let reader = BufReader::new(fh);
-            for line in reader.lines().flatten() {
-                let word = line.trim();
-                let (phones, score) = g2p.g2p(word)?;
-                let phonestr: String = phones.join(" ");
-                if print_scores {
-                    if nlog_probs {
-                        println!("{}\t{}\t{}", word, score, phonestr);
+            let mut errors = match errors {
+                Some(path) => Some(BufWriter::new(File::create(path)?)),
+                None => None,
+            };
+            let mut nwords = 0;
+            let mut nfailed = 0;
+            let jobs = jobs.max(1);
+            let mut lines = reader.lines();
+            let mut block = Vec::<String>::with_capacity(BLOCK_SIZE * jobs);
+            loop {
+                // Decode in blocks as lines are read so that output is
+                // not held back too long
+                block.clear();
+                for line in lines.by_ref().take(BLOCK_SIZE * jobs) {
+                    block.push(line?.trim().to_owned());
+                }
+                if block.is_empty() {
+                    break;
+                }
+                let results = g2p.g2p_batch(&block, nbest, jobs);
+                // Keep going if some words fail, and report them all
+                for (word, prons) in block.iter().zip(results) {
+                    nwords += 1;
+                    let reason = match &prons {
+                        Err(err) => Some(err.to_string()),
+                        Ok(prons) if prons.iter().any(|(_, score)| score.is_infinite()) => {
+                            Some("No pronunciation found".to_owned())
+                        }
+                        Ok(_) => None,
+                    };
+                    if let Some(reason) = reason {
+                        nfailed += 1;
+                        match errors.as_mut() {
+                            Some(fh) => writeln!(fh, "{}\t{}", word, reason)?,
+                            None => eprintln!("Failed on {}: {}", word, reason),
+                        }
+                    }
+                    let source = if print_source {
+                        format!("\t{}", g2p.source(word))
                     } else {
-                        println!("{}\t{}\t{}", word, (-score).exp(), phonestr);
+                        String::new()
+                    };
+                    for (phones, score) in prons.unwrap_or_default() {
+                        let phonestr: String = phones.join(" ");
+                        if print_scores {
+                            if nlog_probs {
+                                println!("{}\t{}\t{}{}", word, score, phonestr, source);
+                            } else {
+                                println!("{}\t{}\t{}{}", word, (-score).exp(), phonestr, source);
+                            }
+                        } else {
+                            println!("{}\t{}{}", word, phonestr, source);
+                        }
+                    }
+                }
+            }
+            if let Some(mut fh) = errors {
+                fh.flush()?;
+            }
+            if nfailed > 0 {
+                return Err(anyhow!("{} of {} words failed", nfailed, nwords));
+            }
+            Ok(())
+        }
+        Commands::Evaluate {
+            reference,
+            hyp,
+            model,
+            folds,
+            order,
+            seq1_max,
+            seq2_max,
+            seq1_del,
+            seq2_del,
+            aligner,
+            ngram,
+            gsep,
+            skip,
+            seq1_sep,
+            seq2_sep,
+            nbest,
+            beam,
+            pmass,
+            jobs,
+            ignore,
+            regex_ignore,
+            ignore_both,
+            verbose,
+        } => {
+            let config = EvaluateConfig {
+                ignore: ignore.split_whitespace().map(str::to_owned).collect(),
+                regex_ignore,
+                ignore_both,
+            };
+            if let Some(folds) = folds {
+                let gsep = gsep.unwrap_or_default();
+                let skip = skip.unwrap_or_else(|| "_".to_owned());
+                let seq1_sep = seq1_sep.unwrap_or_else(|| "|".to_owned());
+                let seq2_sep = seq2_sep.unwrap_or_else(|| "|".to_owned());
+                let fh = File::open(reference)?;
+                let lines = BufReader::new(fh)
+                    .lines()
+                    .collect::<Result<Vec<String>, _>>()?;
+                let ngram_configs: Vec<NGramConfig> = order
+                    .iter()
+                    .map(|&order| NGramConfig {
+                        order,
+                        method: ngram.method,
+                        fractional: ngram.fractional,
+                        seq1_sep: seq1_sep.clone(),
+                        seq2_sep: seq2_sep.clone(),
+                        s1s2_sep: aligner.s1s2_sep.clone(),
+                        skip: skip.clone(),
+                        ..Default::default()
+                    })
+                    .collect();
+                let test_config = TestConfig {
+                    g2p: G2PConfig {
+                        beam,
+                        pmass,
+                        oov: OovPolicy::Empty,
+                        ..Default::default()
+                    },
+                    nbest,
+                    jobs,
+                    evaluate: config,
+                };
+                let mut results = Vec::new();
+                for &seq1_max in &seq1_max {
+                    for &seq2_max in &seq2_max {
+                        for &seq1_del in &seq1_del {
+                            for &seq2_del in &seq2_del {
+                                let align_config = AlignerConfig {
+                                    restrict: aligner.restrict,
+                                    seq1_max,
+                                    seq2_max,
+                                    seq1_del,
+                                    seq2_del,
+                                    seq1_sep: seq1_sep.clone(),
+                                    seq2_sep: seq2_sep.clone(),
+                                    s1s2_sep: aligner.s1s2_sep.clone(),
+                                    eps: aligner.eps.clone(),
+                                    skip: skip.clone(),
+                                    s1_char_delim: gsep.clone(),
+                                    s2_char_delim: aligner.s2_char_delim.clone(),
+                                    penalize: aligner.penalize,
+                                    jobs,
+                                };
+                                let counts = evaluate::cross_validate(
+                                    &lines,
+                                    folds,
+                                    &align_config,
+                                    aligner.iter as usize,
+                                    aligner.tolerance,
+                                    &ngram_configs,
+                                    &test_config,
+                                )?;
+                                for (ngram_config, counts) in ngram_configs.iter().zip(counts) {
+                                    let params = format!(
+                                        "order={} seq1_max={} seq2_max={} seq1_del={} seq2_del={}",
+                                        ngram_config.order, seq1_max, seq2_max, seq1_del, seq2_del
+                                    );
+                                    results.push(report_folds(params, &counts));
+                                }
+                            }
+                        }
+                    }
+                }
+                // Rank by phone error rate, then word error rate
+                if let Some((params, wer, per)) = results
+                    .into_iter()
+                    .min_by(|a, b| (a.2, a.1).partial_cmp(&(b.2, b.1)).unwrap())
+                {
+                    println!("Best: {}", params);
+                    println!("    Mean WER: {:.2}%  PER: {:.2}%", wer, per);
+                }
+                return Ok(());
+            }
+            let refs = evaluate::read_references(&reference)?;
+            let hyps = match (hyp, model) {
+                (Some(hyp), _) => evaluate::read_hypotheses(&hyp)?,
+                (None, Some(model)) => {
+                    let model = Model::read(&model)?;
+                    let g2p = G2P::new(
+                        G2PConfig {
+                            gsep,
+                            skip,
+                            seq1_sep,
+                            seq2_sep,
+                            beam,
+                            pmass,
+                            oov: OovPolicy::Empty,
+                            ..Default::default()
+                        },
+                        model,
+                    )?;
+                    let words: Vec<String> = refs.iter().map(|(word, _)| word.clone()).collect();
+                    let jobs = jobs.max(1);
+                    let results = words
+                        .chunks(BLOCK_SIZE * jobs)
+                        .flat_map(|block| g2p.g2p_batch(block, nbest, jobs));
+                    let mut hyps = Vec::with_capacity(words.len());
+                    for (word, prons) in words.iter().zip(results) {
+                        let prons = prons?
+                            .into_iter()
+                            .map(|(phones, _)| phones.into_iter().map(str::to_owned).collect())
+                            .collect();
+                        hyps.push((word.clone(), prons));
+                    }
+                    hyps
+                }
+                (None, None) => unreachable!(),
+            };
+            let refmap: HashMap<&str, &Vec<Vec<String>>> = refs
+                .iter()
+                .map(|(word, prons)| (word.as_str(), prons))
+                .collect();
+            // Distinct words, then pronunciations on each side
+            let nwords = hyps
+                .iter()
+                .map(|(word, _)| word.as_str())
+                .collect::<HashSet<&str>>()
+                .len();
+            let nprons =
+                |entries: &Entries| -> usize { entries.iter().map(|(_, prons)| prons.len()).sum() };
+            println!(
+                "    Words: {}  Hyps: {} Refs: {}",
+                nwords,
+                nprons(&hyps),
+                nprons(&refs)
+            );
+            let mut evaluator = Evaluator::new(config);
+            for (word, prons) in &hyps {
+                let refprons = refmap
+                    .get(word.as_str())
+                    .ok_or_else(|| anyhow!("No reference for {}", word))?;
+                if verbose {
+                    println!("{}", word);
+                }
+                if let Some(alignment) = evaluator.add(prons, refprons) {
+                    if verbose {
+                        println!("{}\n", alignment);
                     }
-                } else {
-                    println!("{}\t{}", word, phonestr);
                 }
             }
+            println!("{}", evaluator.counts());
             Ok(())
         }
     }
@@ -0,0 +1,381 @@
+use crate::align::Config as AlignerConfig;
+use crate::train::ngram::Config as NGramConfig;
+use crate::train::smooth::Method;
+use crate::StdVectorFst;
+use anyhow::{anyhow, Result};
+use rustfst::prelude::*;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+use std::path::PathBuf;
+
+/// Start of the first line of a model file, followed by the format
+/// version
+const MAGIC: &str = "rustfst-g2p model";
+
+/// Current version of the model file format
+pub const VERSION: u32 = 1;
+
+/// Configuration a model was trained with, stored along with it.
+/// Alignment and training parameters are `None` when they are not
+/// known, e.g. for models converted from ARPA files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    /// Grapheme separator for input words
+    pub gsep: Option<String>,
+    /// Multi-token separator for input tokens
+    pub seq1_sep: String,
+    /// Multi-token separator for output tokens
+    pub seq2_sep: String,
+    /// Token used to separate input-output subsequences
+    pub s1s2_sep: String,
+    /// Skip token used to represent null transitions
+    pub skip: String,
+    /// Maximum length of an input multi-token
+    pub seq1_max: Option<u8>,
+    /// Maximum length of an output multi-token
+    pub seq2_max: Option<u8>,
+    /// Deletion of input tokens was allowed
+    pub seq1_del: Option<bool>,
+    /// Deletion of output tokens was allowed
+    pub seq2_del: Option<bool>,
+    /// Order of N-Grams
+    pub order: Option<u8>,
+    /// Smoothing method
+    pub method: Option<Method>,
+    /// Trained on fractional counts
+    pub fractional: Option<bool>,
+    /// FNV-1a hash of the training data
+    pub data_hash: Option<u64>,
+}
+
+impl Metadata {
+    /// Metadata for a model trained with `ngram_config` on alignments
+    /// of unknown provenance
+    pub fn from_ngram(ngram_config: &NGramConfig) -> Metadata {
+        Metadata {
+            gsep: None,
+            seq1_sep: ngram_config.seq1_sep.clone(),
+            seq2_sep: ngram_config.seq2_sep.clone(),
+            s1s2_sep: ngram_config.s1s2_sep.clone(),
+            skip: ngram_config.skip.clone(),
+            seq1_max: None,
+            seq2_max: None,
+            seq1_del: None,
+            seq2_del: None,
+            order: Some(ngram_config.order),
+            method: Some(ngram_config.method),
+            fractional: Some(ngram_config.fractional),
+            data_hash: None,
+        }
+    }
+
+    /// Metadata for a model aligned with `align_config` and trained
+    /// with `ngram_config`
+    pub fn from_configs(align_config: &AlignerConfig, ngram_config: &NGramConfig) -> Metadata {
+        Metadata {
+            gsep: Some(align_config.s1_char_delim.clone()),
+            seq1_max: Some(align_config.seq1_max),
+            seq2_max: Some(align_config.seq2_max),
+            seq1_del: Some(align_config.seq1_del),
+            seq2_del: Some(align_config.seq2_del),
+            ..Metadata::from_ngram(ngram_config)
+        }
+    }
+
+    /// Fields as key-value pairs, omitting unknown ones
+    fn fields(&self) -> Vec<(&str, String)> {
+        let mut fields = Vec::new();
+        if let Some(gsep) = &self.gsep {
+            fields.push(("gsep", gsep.clone()));
+        }
+        fields.push(("seq1_sep", self.seq1_sep.clone()));
+        fields.push(("seq2_sep", self.seq2_sep.clone()));
+        fields.push(("s1s2_sep", self.s1s2_sep.clone()));
+        fields.push(("skip", self.skip.clone()));
+        let optional = [
+            ("seq1_max", self.seq1_max.map(|v| v.to_string())),
+            ("seq2_max", self.seq2_max.map(|v| v.to_string())),
+            ("seq1_del", self.seq1_del.map(|v| v.to_string())),
+            ("seq2_del", self.seq2_del.map(|v| v.to_string())),
+            ("order", self.order.map(|v| v.to_string())),
+            ("method", self.method.map(|v| v.to_string())),
+            ("fractional", self.fractional.map(|v| v.to_string())),
+            ("data_hash", self.data_hash.map(|v| format!("{:016x}", v))),
+        ];
+        for (key, value) in optional {
+            if let Some(value) = value {
+                fields.push((key, value));
+            }
+        }
+        fields
+    }
+
+    /// Parse fields from key-value pairs.  Unknown keys are ignored,
+    /// so that fields can be added without changing the version.
+    fn from_fields(fields: &[(String, String)]) -> Result<Metadata> {
+        let get = |key: &str| {
+            fields
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.as_str())
+        };
+        let required = |key: &str| {
+            get(key)
+                .map(str::to_owned)
+                .ok_or_else(|| anyhow!("Missing {} in model metadata", key))
+        };
+        fn parse<T: std::str::FromStr>(key: &str, value: Option<&str>) -> Result<Option<T>> {
+            value
+                .map(|v| {
+                    v.parse()
+                        .map_err(|_| anyhow!("Invalid {} in model metadata: {}", key, v))
+                })
+                .transpose()
+        }
+        let data_hash = get("data_hash")
+            .map(|v| {
+                u64::from_str_radix(v, 16)
+                    .map_err(|_| anyhow!("Invalid data_hash in model metadata: {}", v))
+            })
+            .transpose()?;
+        Ok(Metadata {
+            gsep: get("gsep").map(str::to_owned),
+            seq1_sep: required("seq1_sep")?,
+            seq2_sep: required("seq2_sep")?,
+            s1s2_sep: required("s1s2_sep")?,
+            skip: required("skip")?,
+            seq1_max: parse("seq1_max", get("seq1_max"))?,
+            seq2_max: parse("seq2_max", get("seq2_max"))?,
+            seq1_del: parse("seq1_del", get("seq1_del"))?,
+            seq2_del: parse("seq2_del", get("seq2_del"))?,
+            order: parse("order", get("order"))?,
+            method: parse("method", get("method"))?,
+            fractional: parse("fractional", get("fractional"))?,
+            data_hash,
+        })
+    }
+}
+
+/// G2P model, with the configuration it was trained with if known
+#[derive(Debug)]
+pub struct Model {
+    pub metadata: Option<Metadata>,
+    /// Transducer from graphemes to phonemes, with both symbol tables
+    pub fst: StdVectorFst,
+}
+
+/// A bare transducer, as written by older versions or Phonetisaurus,
+/// has no metadata
+impl From<StdVectorFst> for Model {
+    fn from(fst: StdVectorFst) -> Model {
+        Model {
+            metadata: None,
+            fst,
+        }
+    }
+}
+
+impl Model {
+    /// Read a model file, or a bare transducer in OpenFST format
+    pub fn read(input: &PathBuf) -> Result<Model> {
+        let mut data = Vec::new();
+        File::open(input)?.read_to_end(&mut data)?;
+        if !data.starts_with(MAGIC.as_bytes()) {
+            return Ok(Model::from(StdVectorFst::load(&data)?));
+        }
+        let mut reader = BufReader::new(data.as_slice());
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let version: u32 = line[MAGIC.len()..]
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("Invalid model header: {}", line.trim()))?;
+        if version > VERSION {
+            return Err(anyhow!(
+                "Model format version {} is newer than supported version {}",
+                version,
+                VERSION
+            ));
+        }
+        // Metadata follows as key=value lines, up to an empty line
+        let mut fields = Vec::new();
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                return Err(anyhow!("Model file ends in metadata"));
+            }
+            let field = line.strip_suffix('\n').unwrap_or(&line);
+            if field.is_empty() {
+                break;
+            }
+            let (key, value) = field
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Malformed line in model metadata: {}", field))?;
+            fields.push((key.to_owned(), value.to_owned()));
+        }
+        let metadata = Metadata::from_fields(&fields)?;
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest)?;
+        Ok(Model {
+            metadata: Some(metadata),
+            fst: StdVectorFst::load(&rest)?,
+        })
+    }
+
+    /// Write a model file, or a bare transducer if there is no
+    /// metadata
+    pub fn write(&self, output: &PathBuf) -> Result<()> {
+        let metadata = match &self.metadata {
+            Some(metadata) => metadata,
+            None => return self.fst.write(output),
+        };
+        let mut writer = BufWriter::new(File::create(output)?);
+        writeln!(writer, "{} {}", MAGIC, VERSION)?;
+        for (key, value) in metadata.fields() {
+            if value.contains('\n') {
+                return Err(anyhow!("Newline in model metadata {}", key));
+            }
+            writeln!(writer, "{}={}", key, value)?;
+        }
+        writeln!(writer)?;
+        self.fst.store(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// FNV-1a hash of some data
+pub fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// FNV-1a hash of the contents of a file
+pub fn hash_file(input: &PathBuf) -> Result<u64> {
+    let mut data = Vec::new();
+    File::open(input)?.read_to_end(&mut data)?;
+    Ok(fnv1a(&data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::sync::Arc;
+    use tempfile::NamedTempFile;
+
+    fn fst() -> StdVectorFst {
+        let mut fst = StdVectorFst::new();
+        let (q0, q1) = (fst.add_state(), fst.add_state());
+        fst.set_start(q0).unwrap();
+        fst.emplace_tr(q0, 2, 2, 1.5, q1).unwrap();
+        fst.set_final(q1, 0.0).unwrap();
+        let syms = Arc::new(symt!["|", "a}EY"]);
+        fst.set_input_symbols(Arc::clone(&syms));
+        fst.set_output_symbols(syms);
+        fst
+    }
+
+    fn metadata() -> Metadata {
+        Metadata {
+            gsep: Some(String::new()),
+            seq1_sep: "|".to_owned(),
+            seq2_sep: "|".to_owned(),
+            s1s2_sep: "}".to_owned(),
+            skip: "_".to_owned(),
+            seq1_max: Some(2),
+            seq2_max: Some(1),
+            seq1_del: Some(true),
+            seq2_del: Some(false),
+            order: Some(7),
+            method: Some(Method::WittenBell),
+            fractional: Some(false),
+            data_hash: Some(fnv1a(b"a")),
+        }
+    }
+
+    /// Read a model file with the given header, metadata lines and
+    /// the test transducer
+    fn read_with_header(header: &str) -> Result<Model> {
+        let file = NamedTempFile::new().unwrap();
+        let mut data = header.as_bytes().to_vec();
+        fst().store(&mut data).unwrap();
+        fs::write(file.path(), data).unwrap();
+        Model::read(&file.path().to_path_buf())
+    }
+
+    #[test]
+    fn round_trip() {
+        let file = NamedTempFile::new().unwrap();
+        let path = file.path().to_path_buf();
+        let model = Model {
+            metadata: Some(metadata()),
+            fst: fst(),
+        };
+        model.write(&path).unwrap();
+        let read = Model::read(&path).unwrap();
+        assert_eq!(read.metadata, Some(metadata()));
+        assert_eq!(read.fst, fst());
+        // Without metadata, a bare transducer
+        Model::from(fst()).write(&path).unwrap();
+        assert_eq!(StdVectorFst::read(&path).unwrap(), fst());
+        let read = Model::read(&path).unwrap();
+        assert_eq!(read.metadata, None);
+        assert_eq!(read.fst, fst());
+    }
+
+    #[test]
+    fn bad_magic() {
+        // Taken for a bare transducer, which it is not
+        let header = "rustfst-g2p modle 1\nseq1_sep=|\n\n";
+        assert!(read_with_header(header).is_err());
+        let err = read_with_header("rustfst-g2p model one\n\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid model header: rustfst-g2p model one"
+        );
+    }
+
+    #[test]
+    fn newer_version() {
+        let header = format!("{} {}\nseq1_sep=|\n\n", MAGIC, VERSION + 1);
+        let err = read_with_header(&header).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Model format version {} is newer than supported version {}",
+                VERSION + 1,
+                VERSION
+            )
+        );
+    }
+
+    #[test]
+    fn unknown_keys() {
+        let header = format!(
+            "{} {}\ncolour=blue\nseq1_sep=|\nseq2_sep=|\ns1s2_sep=}}\nskip=_\norder=3\nfuture=a=b\n\n",
+            MAGIC, VERSION
+        );
+        let model = read_with_header(&header).unwrap();
+        let metadata = model.metadata.unwrap();
+        assert_eq!(metadata.order, Some(3));
+        assert_eq!(metadata.gsep, None);
+        assert_eq!(metadata.method, None);
+        assert_eq!(model.fst, fst());
+        // But known keys must still be valid
+        let header = format!(
+            "{} {}\nseq1_sep=|\nseq2_sep=|\ns1s2_sep=}}\nskip=_\norder=three\n\n",
+            MAGIC, VERSION
+        );
+        let err = read_with_header(&header).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid order in model metadata: three");
+    }
+
+    #[test]
+    fn fnv1a_values() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+    }
+}
@@ -0,0 +1,387 @@
+use super::backoff::{BackoffModel, BackoffState, END};
+use super::counts::{HistoryStates, START};
+use anyhow::{anyhow, Result};
+use rustfst::prelude::*;
+use std::collections::HashMap;
+use std::io::prelude::*;
+
+/// N-Grams found in an ARPA file for one history
+struct ArpaState {
+    /// Probabilities of following tokens
+    ngrams: Vec<(Label, f64)>,
+    /// Backoff weight
+    backoff: f64,
+}
+
+impl Default for ArpaState {
+    fn default() -> ArpaState {
+        ArpaState {
+            ngrams: Vec::new(),
+            backoff: 1.0,
+        }
+    }
+}
+
+/// Read a backoff N-Gram model in ARPA format
+///
+/// Tokens other than `<s>` and `</s>` are added to `syms`.  N-Grams
+/// containing `<unk>` (which SRILM and KenLM always include) are
+/// ignored, since it cannot be split into input and output tokens.
+/// The resulting structure is the same as that of a model created
+/// by `NGram::train`.
+pub(crate) fn read_arpa<R: BufRead>(reader: R, syms: &mut SymbolTable) -> Result<BackoffModel> {
+    let mut arpa = HistoryStates::<ArpaState>::default();
+    arpa.state(&[START]);
+    arpa.state(&[]);
+    let mut order = 0;
+    let mut max_order = 0;
+    let mut seen_data = false;
+    for (lineno, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line == "\\data\\" {
+            seen_data = true;
+            continue;
+        }
+        if line == "\\end\\" {
+            break;
+        }
+        if let Some(n) = line
+            .strip_prefix('\\')
+            .and_then(|s| s.strip_suffix("-grams:"))
+        {
+            order = n
+                .parse()
+                .map_err(|_| anyhow!("Invalid section header at line {}: {}", lineno + 1, line))?;
+            if order == 0 || order > max_order {
+                return Err(anyhow!("Unexpected {}-grams at line {}", order, lineno + 1));
+            }
+            continue;
+        }
+        if order == 0 {
+            // Only the highest order is needed from the \data\ section
+            let n = line
+                .strip_prefix("ngram ")
+                .and_then(|s| s.split_once('='))
+                .and_then(|(n, _)| n.trim().parse::<usize>().ok());
+            match n {
+                Some(n) if seen_data => max_order = max_order.max(n),
+                _ => {
+                    return Err(anyhow!(
+                        "Unexpected line {} in header: {}",
+                        lineno + 1,
+                        line
+                    ))
+                }
+            }
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != order + 1 && fields.len() != order + 2 {
+            return Err(anyhow!(
+                "Expected {}-gram at line {}: {}",
+                order,
+                lineno + 1,
+                line
+            ));
+        }
+        let parse_log = |s: &str| -> Result<f64> {
+            let logprob: f64 = s
+                .parse()
+                .map_err(|_| anyhow!("Invalid number {} at line {}", s, lineno + 1))?;
+            Ok(10f64.powf(logprob))
+        };
+        let prob = parse_log(fields[0])?;
+        let tokens = &fields[1..=order];
+        if tokens.contains(&"<unk>") {
+            continue;
+        }
+        let labels: Vec<Label> = tokens
+            .iter()
+            .map(|&t| match t {
+                "<s>" => START,
+                "</s>" => END,
+                _ => syms.add_symbol(t),
+            })
+            .collect();
+        let (&label, history) = labels.split_last().unwrap();
+        // The probability of <s> is meaningless, but its backoff
+        // weight is that of the start state
+        if !(tokens[order - 1] == "<s>" && order == 1) {
+            let q = arpa.state(history);
+            arpa.data[q as usize].ngrams.push((label, prob));
+        }
+        // Only N-Grams of lower orders can be histories
+        if order < max_order && tokens[order - 1] != "</s>" {
+            let q = arpa.state(&labels);
+            if let Some(backoff) = fields.get(order + 1) {
+                arpa.data[q as usize].backoff = parse_log(backoff)?;
+            }
+        }
+    }
+    if max_order == 0 {
+        return Err(anyhow!("No N-Grams found in ARPA file"));
+    }
+    let mut model = BackoffModel {
+        start: arpa.ids[[START].as_slice()],
+        states: Vec::with_capacity(arpa.histories.len()),
+    };
+    for (q, (history, state)) in arpa.histories.iter().zip(&arpa.data).enumerate() {
+        let mut ngrams = Vec::with_capacity(state.ngrams.len());
+        for &(label, prob) in &state.ngrams {
+            let nextstate = if label == END {
+                q as StateId
+            } else {
+                let mut next = history.clone();
+                next.push(label);
+                arpa.longest_suffix(&next)
+            };
+            ngrams.push((label, prob, nextstate));
+        }
+        ngrams.sort_by_key(|&(label, _, _)| label);
+        model.states.push(BackoffState {
+            backoff: if history.is_empty() {
+                None
+            } else {
+                Some(arpa.longest_suffix(&history[1..]))
+            },
+            backoff_weight: state.backoff,
+            depth: 0,
+            ngrams,
+            fractional: Vec::new(),
+        });
+    }
+    model.update_depths()?;
+    Ok(model)
+}
+
+/// Write a backoff N-Gram model in ARPA format
+pub(crate) fn write_arpa<W: Write>(
+    model: &BackoffModel,
+    syms: &SymbolTable,
+    mut writer: W,
+) -> Result<()> {
+    // Recover the history of each state from the transitions leading
+    // to it, starting from the unigram and start states
+    let unigram = model
+        .states
+        .iter()
+        .position(|s| s.backoff.is_none())
+        .ok_or_else(|| anyhow!("No unigram state in model"))?;
+    let mut histories: Vec<Option<Vec<Label>>> = vec![None; model.states.len()];
+    histories[unigram] = Some(Vec::new());
+    if model.start as usize != unigram {
+        histories[model.start as usize] = Some(vec![START]);
+    }
+    let mut queue = vec![unigram, model.start as usize];
+    while let Some(q) = queue.pop() {
+        let history = histories[q].clone().unwrap();
+        for &(label, _, nextstate) in &model.states[q].ngrams {
+            let next = nextstate as usize;
+            if label == END || histories[next].is_some() {
+                continue;
+            }
+            let mut h = history.clone();
+            h.push(label);
+            let depth = model.states[next].depth;
+            histories[next] = Some(h[h.len().saturating_sub(depth)..].to_vec());
+            queue.push(next);
+        }
+    }
+    let mut states = HashMap::<&[Label], usize>::new();
+    for (q, history) in histories.iter().enumerate() {
+        match history {
+            Some(h) => {
+                states.insert(h, q);
+            }
+            None => return Err(anyhow!("State {} is not reachable", q)),
+        }
+    }
+    // <s> and </s> have the same label, but only <s> occurs in
+    // histories
+    let token = |label: Label| -> Result<&str> {
+        match label {
+            START => Ok("<s>"),
+            _ => syms
+                .get_symbol(label)
+                .ok_or_else(|| anyhow!("Label {} not found in symbol table", label)),
+        }
+    };
+    let log10 = |p: f64| if p > 0.0 { p.log10() } else { -99.0 };
+    // Collect the lines for each order, along with the N-Gram for <s>
+    let mut orders = Vec::<Vec<String>>::new();
+    let start_backoff = model.states[model.start as usize].backoff_weight;
+    orders.push(vec![format!(
+        "{:.6}\t<s>\t{:.6}",
+        -99.0,
+        log10(start_backoff)
+    )]);
+    for (q, state) in model.states.iter().enumerate() {
+        let history = histories[q].as_ref().unwrap();
+        for &(label, prob, _) in &state.ngrams {
+            let mut tokens = history
+                .iter()
+                .map(|&l| token(l))
+                .collect::<Result<Vec<&str>>>()?;
+            tokens.push(if label == END { "</s>" } else { token(label)? });
+            let mut ngram = history.clone();
+            ngram.push(label);
+            let mut line = format!("{:.6}\t{}", log10(prob), tokens.join(" "));
+            if label != END {
+                if let Some(&next) = states.get(ngram.as_slice()) {
+                    line += &format!("\t{:.6}", log10(model.states[next].backoff_weight));
+                }
+            }
+            if orders.len() < ngram.len() {
+                orders.resize(ngram.len(), Vec::new());
+            }
+            orders[ngram.len() - 1].push(line);
+        }
+    }
+    writeln!(writer, "\\data\\")?;
+    for (n, lines) in orders.iter().enumerate() {
+        writeln!(writer, "ngram {}={}", n + 1, lines.len())?;
+    }
+    for (n, lines) in orders.iter().enumerate() {
+        writeln!(writer, "\n\\{}-grams:", n + 1)?;
+        for line in lines {
+            writeln!(writer, "{}", line)?;
+        }
+    }
+    writeln!(writer, "\n\\end\\")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::train::counts::NGramCounts;
+    use crate::train::smooth::{smooth, Method};
+    use crate::StdVectorFst;
+
+    /// Probability of a token after a state, and the next state
+    fn step(model: &BackoffModel, mut q: StateId, label: Label) -> (f64, StateId) {
+        let mut scale = 1.0;
+        loop {
+            let state = &model.states[q as usize];
+            if let Ok(idx) = state.ngrams.binary_search_by_key(&label, |&(l, _, _)| l) {
+                let (_, prob, next) = state.ngrams[idx];
+                return (scale * prob, next);
+            }
+            scale *= state.backoff_weight;
+            q = state.backoff.unwrap();
+        }
+    }
+
+    /// Probability of a sentence of symbols, including `</s>`
+    fn sentence_prob(model: &BackoffModel, syms: &SymbolTable, sentence: &[&str]) -> f64 {
+        let mut q = model.start;
+        let mut total = 1.0;
+        for &sym in sentence {
+            let (prob, next) = step(model, q, syms.get_label(sym).unwrap());
+            total *= prob;
+            q = next;
+        }
+        total * step(model, q, END).0
+    }
+
+    fn arpa_text(model: &BackoffModel, syms: &SymbolTable) -> String {
+        let mut text = Vec::new();
+        write_arpa(model, syms, &mut text).unwrap();
+        String::from_utf8(text).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let syms = symt!["a", "b", "c"];
+        let mut counts = NGramCounts::new(3);
+        let sentences: [&[Label]; 5] = [&[1, 2], &[1, 2, 3], &[1, 3, 3], &[2, 3], &[1]];
+        for sentence in sentences {
+            counts.add_sentence(sentence, 1.0);
+        }
+        let mut model = counts.to_model();
+        smooth(&mut model, Method::KneserNey).unwrap();
+        let text = arpa_text(&model, &syms);
+        let mut read_syms = SymbolTable::new();
+        let read = read_arpa(text.as_bytes(), &mut read_syms).unwrap();
+        // Same structure, and the same probabilities up to rounding
+        let (fst, read_fst) = (model.to_fst().unwrap(), read.to_fst().unwrap());
+        assert_eq!(fst.num_states(), read_fst.num_states());
+        let num_trs = |fst: &StdVectorFst| -> usize {
+            fst.states_iter().map(|q| fst.num_trs(q).unwrap()).sum()
+        };
+        assert_eq!(num_trs(&fst), num_trs(&read_fst));
+        let tokens = ["a", "b", "c"];
+        let mut sentences = vec![vec![]];
+        for len in 1..=3 {
+            for i in 0..tokens.len().pow(len) {
+                let sentence = (0..len)
+                    .map(|j| tokens[i / tokens.len().pow(j) % tokens.len()])
+                    .collect();
+                sentences.push(sentence);
+            }
+        }
+        for sentence in &sentences {
+            let expected = sentence_prob(&model, &syms, sentence);
+            let actual = sentence_prob(&read, &read_syms, sentence);
+            assert!(
+                (actual / expected - 1.0).abs() < 1e-4,
+                "P({:?}) = {} != {}",
+                sentence,
+                actual,
+                expected
+            );
+        }
+        // N-Grams of each order may be in a different order
+        let lines = |text: &str| {
+            let mut lines: Vec<String> = text.lines().map(str::to_owned).collect();
+            lines.sort();
+            lines
+        };
+        assert_eq!(lines(&arpa_text(&read, &read_syms)), lines(&text));
+    }
+
+    #[test]
+    fn srilm_unk_and_start_backoff() {
+        let text = "\\data\\
+ngram 1=5
+ngram 2=4
+
+\\1-grams:
+-1.0\t<unk>
+-99\t<s>\t-0.25
+-0.5\ta\t-0.2
+-0.6\tb
+-0.7\t</s>
+
+\\2-grams:
+-0.1\t<s> a
+-0.2\ta b
+-0.3\t<unk> a
+-0.4\ta <unk>
+
+\\end\\
+";
+        let mut syms = SymbolTable::new();
+        let model = read_arpa(text.as_bytes(), &mut syms).unwrap();
+        assert!(syms.get_label("<unk>").is_none());
+        let (a, b) = (syms.get_label("a").unwrap(), syms.get_label("b").unwrap());
+        let start = &model.states[model.start as usize];
+        assert!((start.backoff_weight - 10f64.powf(-0.25)).abs() < 1e-9);
+        assert_eq!(start.ngrams.len(), 1);
+        let close = |x: f64, y: f64| (x - y).abs() < 1e-9;
+        assert!(close(step(&model, model.start, a).0, 10f64.powf(-0.1)));
+        // Unseen after <s>, so backs off with its weight
+        let (p, q) = step(&model, model.start, b);
+        assert!(close(p, 10f64.powf(-0.25 - 0.6)));
+        // The unigram state has a, b and </s>, but not <s> or <unk>
+        let unigram = &model.states[model.states[q as usize].backoff.unwrap() as usize];
+        assert_eq!(unigram.ngrams.len(), 3);
+        let (_, qa) = step(&model, model.start, a);
+        assert!(close(step(&model, qa, b).0, 10f64.powf(-0.2)));
+        assert!(close(step(&model, qa, END).0, 10f64.powf(-0.2 - 0.7)));
+    }
+}
@@ -0,0 +1,205 @@
+use crate::StdVectorFst;
+use anyhow::{anyhow, Result};
+use rustfst::prelude::*;
+
+/// Label used for `</s>` in the N-Grams of a state, which is really
+/// its final weight.  This is safe since epsilon transitions are
+/// always backoff transitions.
+pub(crate) const END: Label = EPS_LABEL;
+
+/// Expected count of an N-Gram, along with the probabilities of it
+/// having been seen exactly 0 to 4 times, which are needed to
+/// estimate Kneser-Ney models from fractional counts (Zhang & Chiang,
+/// 2014)
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FractionalCount {
+    /// Expected count
+    pub expected: f64,
+    /// Probability of each count up to 4 (the remainder is the
+    /// probability of counts of 5 or more)
+    pub probs: [f64; 5],
+}
+
+impl FractionalCount {
+    /// A count which is certainly zero
+    pub fn zero() -> FractionalCount {
+        FractionalCount {
+            expected: 0.0,
+            probs: [1.0, 0.0, 0.0, 0.0, 0.0],
+        }
+    }
+
+    /// Approximate distribution for an expected count, where the
+    /// integer part is certain and the fractional part is the
+    /// probability of one more occurrence
+    pub fn from_expected(count: f64) -> FractionalCount {
+        let mut fc = FractionalCount::zero();
+        fc.add_expected(count);
+        fc
+    }
+
+    /// Add an occurrence with probability `p`
+    pub fn add(&mut self, p: f64) {
+        for r in (1..self.probs.len()).rev() {
+            self.probs[r] = self.probs[r] * (1.0 - p) + self.probs[r - 1] * p;
+        }
+        self.probs[0] *= 1.0 - p;
+        self.expected += p;
+    }
+
+    /// Add an expected count (as in `from_expected`)
+    pub fn add_expected(&mut self, count: f64) {
+        let whole = count.floor();
+        for _ in 0..(whole as usize).min(self.probs.len()) {
+            self.add(1.0);
+        }
+        // Beyond the tracked counts only the expectation matters
+        self.expected += whole - whole.min(self.probs.len() as f64);
+        self.add(count - whole);
+    }
+
+    /// Probability of having been seen at all
+    pub fn seen(&self) -> f64 {
+        1.0 - self.probs[0]
+    }
+}
+
+/// A state (i.e. history) in a backoff N-Gram model or count FST
+#[derive(Debug, Clone)]
+pub(crate) struct BackoffState {
+    /// State to back off to, or None for the unigram state
+    pub backoff: Option<StateId>,
+    /// Backoff weight (probability, not negative log)
+    pub backoff_weight: f64,
+    /// Length of the history, which is also the number of backoff
+    /// transitions to get to the unigram state
+    pub depth: usize,
+    /// Counts or probabilities (not negative logs) of the following
+    /// tokens, as (label, value, next state), with `END` for `</s>`,
+    /// sorted by label
+    pub ngrams: Vec<(Label, f64, StateId)>,
+    /// Fractional counts for each N-Gram, if known (otherwise empty)
+    pub fractional: Vec<FractionalCount>,
+}
+
+/// A backoff N-Gram model or count FST, in a form which is more
+/// convenient for smoothing than the FST itself
+#[derive(Debug, Clone)]
+pub(crate) struct BackoffModel {
+    /// State for the `<s>` history
+    pub start: StateId,
+    /// All states, indexed by their ID in the FST
+    pub states: Vec<BackoffState>,
+}
+
+impl BackoffModel {
+    /// Read the structure and weights of an N-Gram FST
+    pub fn from_fst(fst: &StdVectorFst) -> Result<BackoffModel> {
+        let start = fst
+            .start()
+            .ok_or_else(|| anyhow!("N-Gram FST has no start state"))?;
+        let mut states = Vec::<BackoffState>::with_capacity(fst.num_states());
+        for q in fst.states_iter() {
+            let mut state = BackoffState {
+                backoff: None,
+                backoff_weight: 0.0,
+                depth: 0,
+                ngrams: Vec::new(),
+                fractional: Vec::new(),
+            };
+            for tr in fst.get_trs(q)?.trs() {
+                let value = (-*tr.weight.value() as f64).exp();
+                if tr.ilabel == EPS_LABEL {
+                    if state.backoff.is_some() {
+                        return Err(anyhow!("State {} has multiple backoff transitions", q));
+                    }
+                    state.backoff = Some(tr.nextstate);
+                    state.backoff_weight = value;
+                } else {
+                    state.ngrams.push((tr.ilabel, value, tr.nextstate));
+                }
+            }
+            if let Some(weight) = fst.final_weight(q)? {
+                state.ngrams.push((END, (-*weight.value() as f64).exp(), q));
+            }
+            state.ngrams.sort_by_key(|&(label, _, _)| label);
+            states.push(state);
+        }
+        let mut model = BackoffModel { start, states };
+        model.update_depths()?;
+        Ok(model)
+    }
+
+    /// Set the depth of each state from the length of its backoff chain
+    pub fn update_depths(&mut self) -> Result<()> {
+        for q in 0..self.states.len() {
+            let mut depth = 0;
+            let mut backoff = self.states[q].backoff;
+            while let Some(b) = backoff {
+                depth += 1;
+                if depth > self.states.len() {
+                    return Err(anyhow!("Cycle in backoff transitions from state {}", q));
+                }
+                backoff = self.states[b as usize].backoff;
+            }
+            self.states[q].depth = depth;
+        }
+        Ok(())
+    }
+
+    /// Snap counts read from an FST to the nearest integer when they
+    /// are within rounding error of it, since otherwise the
+    /// count-of-counts used for smoothing miss them
+    pub fn round_counts(&mut self) {
+        for state in self.states.iter_mut() {
+            for (_, count, _) in state.ngrams.iter_mut() {
+                let rounded = count.round();
+                if (*count - rounded).abs() < 1e-5 * rounded {
+                    *count = rounded;
+                }
+            }
+        }
+    }
+
+    /// Find the probability (or count) of a token following a
+    /// state, backing off as necessary.
+    pub fn backoff_value(&self, mut state: StateId, label: Label) -> Result<f64> {
+        let mut scale = 1.0;
+        loop {
+            let s = &self.states[state as usize];
+            if let Ok(idx) = s.ngrams.binary_search_by_key(&label, |&(l, _, _)| l) {
+                return Ok(scale * s.ngrams[idx].1);
+            }
+            match s.backoff {
+                Some(backoff) => {
+                    scale *= s.backoff_weight;
+                    state = backoff;
+                }
+                None => return Err(anyhow!("Label {} not found in unigram state", label)),
+            }
+        }
+    }
+
+    /// Create an FST with the same structure and new weights
+    pub fn to_fst(&self) -> Result<StdVectorFst> {
+        let mut fst = StdVectorFst::new();
+        fst.add_states(self.states.len());
+        fst.set_start(self.start)?;
+        for (q, state) in self.states.iter().enumerate() {
+            let q = q as StateId;
+            for &(label, value, nextstate) in &state.ngrams {
+                let weight = TropicalWeight::new(-value.ln() as f32);
+                if label == END {
+                    fst.set_final(q, weight)?;
+                } else {
+                    fst.add_tr(q, Tr::new(label, label, weight, nextstate))?;
+                }
+            }
+            if let Some(backoff) = state.backoff {
+                let weight = TropicalWeight::new(-state.backoff_weight.ln() as f32);
+                fst.add_tr(q, Tr::new(EPS_LABEL, EPS_LABEL, weight, backoff))?;
+            }
+        }
+        Ok(fst)
+    }
+}
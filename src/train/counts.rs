@@ -0,0 +1,275 @@
+use super::backoff::{BackoffModel, BackoffState, FractionalCount, END};
+use crate::LogVectorFst;
+use anyhow::{anyhow, Result};
+use rustfst::algorithms::top_sort;
+use rustfst::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+
+/// Label used for `<s>` in histories.  This is safe since epsilon
+/// never occurs in the input sequences.
+pub(crate) const START: Label = EPS_LABEL;
+
+/// Posterior probability below which partial paths in alignment
+/// lattices are ignored, since otherwise every unlikely alignment
+/// creates new histories
+const MIN_POSTERIOR: f64 = 1e-4;
+
+/// States of a backoff model indexed by history, with some data
+/// for each state
+#[derive(Default)]
+pub(crate) struct HistoryStates<T> {
+    /// Mapping of histories to state IDs
+    pub ids: HashMap<Vec<Label>, StateId>,
+    /// History for each state
+    pub histories: Vec<Vec<Label>>,
+    /// Data for each state
+    pub data: Vec<T>,
+}
+
+impl<T: Default> HistoryStates<T> {
+    /// Get or create the state for a history
+    pub fn state(&mut self, history: &[Label]) -> StateId {
+        if let Some(&state) = self.ids.get(history) {
+            return state;
+        }
+        let state = self.histories.len() as StateId;
+        self.ids.insert(history.to_vec(), state);
+        self.histories.push(history.to_vec());
+        self.data.push(T::default());
+        state
+    }
+}
+
+impl<T> HistoryStates<T> {
+    /// Find the state for the longest suffix of a history, which
+    /// must have a state for the empty history
+    pub fn longest_suffix(&self, mut history: &[Label]) -> StateId {
+        loop {
+            if let Some(&state) = self.ids.get(history) {
+                return state;
+            }
+            history = &history[1..];
+        }
+    }
+}
+
+/// N-Gram counts indexed by history, used to build count FSTs
+pub(crate) struct NGramCounts {
+    /// Maximum length of a history (i.e. order - 1)
+    max_history: usize,
+    /// Counts of following tokens for each history
+    states: HistoryStates<HashMap<Label, FractionalCount>>,
+}
+
+impl NGramCounts {
+    pub fn new(order: u8) -> NGramCounts {
+        let mut counts = NGramCounts {
+            max_history: (order as usize).saturating_sub(1),
+            states: HistoryStates::default(),
+        };
+        // Make sure the start and unigram states exist even for empty input
+        counts.states.state(&counts.start_history());
+        counts.states.state(&[]);
+        counts
+    }
+
+    /// History at the start of a sentence
+    fn start_history(&self) -> Vec<Label> {
+        let mut history = vec![START];
+        history.truncate(self.max_history);
+        history
+    }
+
+    /// History after adding a token
+    fn next_history(&self, history: &[Label], label: Label) -> Vec<Label> {
+        let mut next = history.to_vec();
+        next.push(label);
+        if next.len() > self.max_history {
+            next.remove(0);
+        }
+        next
+    }
+
+    /// Add the expected counts of N-Grams in one sentence
+    fn add_expected(&mut self, expected: HashMap<(StateId, Label), f64>) {
+        for ((state, label), count) in expected {
+            self.states.data[state as usize]
+                .entry(label)
+                .or_insert_with(FractionalCount::zero)
+                .add_expected(count);
+        }
+    }
+
+    /// Add counts for all N-Grams (including boundaries) in a sentence
+    pub fn add_sentence(&mut self, labels: &[Label], count: f64) {
+        let mut expected = HashMap::<(StateId, Label), f64>::new();
+        let mut history = self.start_history();
+        for &label in labels.iter().chain([END].iter()) {
+            for start in 0..=history.len() {
+                let state = self.states.state(&history[start..]);
+                *expected.entry((state, label)).or_insert(0.0) += count;
+            }
+            history = self.next_history(&history, label);
+        }
+        self.add_expected(expected);
+    }
+
+    /// Add expected counts for all N-Grams (including boundaries) in
+    /// all paths of an acyclic lattice, weighted by their posterior
+    /// probabilities.
+    ///
+    /// This is done by expanding the lattice with the histories
+    /// leading to each state, since the backward probabilities only
+    /// depend on the lattice state.  Histories with very low
+    /// posterior probability are pruned.
+    pub fn add_lattice(&mut self, lattice: &LogVectorFst) -> Result<()> {
+        let mut lattice = lattice.clone();
+        top_sort(&mut lattice)?;
+        let start = match lattice.start() {
+            Some(start) => start,
+            None => return Ok(()),
+        };
+        let beta: Vec<f64> = shortest_distance(&lattice, true)?
+            .iter()
+            .map(|w| (-*w.value() as f64).exp())
+            .collect();
+        let total = beta[start as usize];
+        if total <= 0.0 || !total.is_finite() {
+            return Err(anyhow!("Lattice has no successful paths"));
+        }
+        // Use ordered maps to create states in a consistent order
+        let mut alpha: Vec<BTreeMap<Vec<Label>, f64>> = vec![BTreeMap::new(); lattice.num_states()];
+        alpha[start as usize].insert(self.start_history(), 1.0);
+        let mut expected = HashMap::<(StateId, Label), f64>::new();
+        for q in lattice.states_iter() {
+            let histories = std::mem::take(&mut alpha[q as usize]);
+            for (history, a) in histories {
+                if a * beta[q as usize] / total < MIN_POSTERIOR {
+                    continue;
+                }
+                let suffixes: Vec<StateId> = (0..=history.len())
+                    .map(|start| self.states.state(&history[start..]))
+                    .collect();
+                let mut add = |label: Label, posterior: f64| {
+                    for &state in &suffixes {
+                        *expected.entry((state, label)).or_insert(0.0) += posterior;
+                    }
+                };
+                if let Some(weight) = lattice.final_weight(q)? {
+                    add(END, a * (-*weight.value() as f64).exp() / total);
+                }
+                for tr in lattice.get_trs(q)?.trs() {
+                    let p = a * (-*tr.weight.value() as f64).exp();
+                    add(tr.ilabel, p * beta[tr.nextstate as usize] / total);
+                    let next = self.next_history(&history, tr.ilabel);
+                    *alpha[tr.nextstate as usize].entry(next).or_insert(0.0) += p;
+                }
+            }
+        }
+        self.add_expected(expected);
+        Ok(())
+    }
+
+    /// Create the backoff structure for these counts
+    ///
+    /// This has one state per history (including the `<s>` history,
+    /// which is the start state), with transitions for the counts of
+    /// each N-Gram going to the state for the longest history that
+    /// was seen, and backoff transitions (with zero count) from each
+    /// history to its longest proper suffix.
+    pub fn to_model(&self) -> BackoffModel {
+        let mut states = Vec::<BackoffState>::with_capacity(self.states.histories.len());
+        for (state, history) in self.states.histories.iter().enumerate() {
+            let mut counts: Vec<(&Label, &FractionalCount)> =
+                self.states.data[state].iter().collect();
+            counts.sort_by_key(|(&label, _)| label);
+            let mut ngrams = Vec::with_capacity(counts.len());
+            let mut fractional = Vec::with_capacity(counts.len());
+            for (&label, &count) in counts {
+                let nextstate = if label == END {
+                    state as StateId
+                } else {
+                    // Back off to the longest history that was seen
+                    self.states
+                        .longest_suffix(&self.next_history(history, label))
+                };
+                ngrams.push((label, count.expected, nextstate));
+                fractional.push(count);
+            }
+            states.push(BackoffState {
+                backoff: if history.is_empty() {
+                    None
+                } else {
+                    Some(self.states.ids[&history[1..]])
+                },
+                backoff_weight: 0.0,
+                depth: history.len(),
+                ngrams,
+                fractional,
+            });
+        }
+        BackoffModel {
+            start: self.states.ids[&self.start_history()],
+            states,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Counts = Vec<(Label, f64, [f64; 5])>;
+
+    /// Counts with their fractional distributions, for each history
+    fn counts(counts: &NGramCounts) -> Vec<(Vec<Label>, Counts)> {
+        counts
+            .states
+            .histories
+            .iter()
+            .zip(&counts.states.data)
+            .map(|(history, counts)| {
+                let mut counts: Vec<_> = counts
+                    .iter()
+                    .map(|(&label, count)| (label, count.expected, count.probs))
+                    .collect();
+                counts.sort_by_key(|&(label, _, _)| label);
+                (history.clone(), counts)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn single_path_lattice() {
+        let labels = [1, 2, 1, 1, 3];
+        let mut sentence = NGramCounts::new(3);
+        sentence.add_sentence(&labels, 1.0);
+        sentence.add_sentence(&labels[..2], 1.0);
+        // The weights of the only path do not matter
+        let mut lattice = LogVectorFst::new();
+        let mut q = lattice.add_state();
+        lattice.set_start(q).unwrap();
+        for &label in &labels {
+            let next = lattice.add_state();
+            lattice.emplace_tr(q, label, label, 0.5, next).unwrap();
+            q = next;
+        }
+        lattice.set_final(q, 2.0).unwrap();
+        let mut fractional = NGramCounts::new(3);
+        fractional.add_lattice(&lattice).unwrap();
+        fractional.add_sentence(&labels[..2], 1.0);
+        let (expected, actual) = (counts(&sentence), counts(&fractional));
+        assert_eq!(expected.len(), actual.len());
+        for ((h1, c1), (h2, c2)) in expected.iter().zip(&actual) {
+            assert_eq!(h1, h2);
+            assert_eq!(c1.len(), c2.len(), "Counts after {:?}", h1);
+            for ((l1, e1, p1), (l2, e2, p2)) in c1.iter().zip(c2) {
+                assert_eq!(l1, l2);
+                assert!((e1 - e2).abs() < 1e-9, "Count of {} after {:?}", l1, h1);
+                for (p1, p2) in p1.iter().zip(p2) {
+                    assert!((p1 - p2).abs() < 1e-9, "Count of {} after {:?}", l1, h1);
+                }
+            }
+        }
+    }
+}
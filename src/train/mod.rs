@@ -1 +1,138 @@
+pub(crate) mod arpa;
+pub(crate) mod backoff;
+pub(crate) mod counts;
 pub mod ngram;
+pub mod smooth;
+
+use crate::align::{Aligner, Config as AlignerConfig};
+use crate::model::{hash_file, Metadata, Model};
+use crate::StdVectorFst;
+use anyhow::{anyhow, Result};
+use ngram::{Config as NGramConfig, NGram};
+use std::path::PathBuf;
+
+/// Align a pronunciation dictionary and train a G2P model on the
+/// alignments, as `align` followed by `train` would, but without
+/// writing the alignments out.  EM runs for at most `iter`
+/// iterations, or until the log-likelihood improves by less than
+/// `tolerance`.  The separators and skip token in both
+/// configurations must match.  The model records both
+/// configurations and a hash of the dictionary.
+pub fn train_dictionary(
+    input: &PathBuf,
+    align_config: AlignerConfig,
+    iter: usize,
+    tolerance: Option<f64>,
+    ngram_config: NGramConfig,
+) -> Result<Model> {
+    // Check before doing EM for nothing
+    check_configs(&align_config, &ngram_config)?;
+    let metadata = Metadata {
+        data_hash: Some(hash_file(input)?),
+        ..Metadata::from_configs(&align_config, &ngram_config)
+    };
+    let mut aligner = Aligner::new(align_config);
+    aligner.load_dictionary(input)?;
+    aligner.em(iter, tolerance)?;
+    Ok(Model {
+        metadata: Some(metadata),
+        fst: train_aligned(&aligner, ngram_config)?,
+    })
+}
+
+/// Train a G2P model on the alignments from an aligner that has
+/// already been run
+pub fn train_aligned(aligner: &Aligner, ngram_config: NGramConfig) -> Result<StdVectorFst> {
+    check_configs(&aligner.config, &ngram_config)?;
+    let mut trainer = NGram::new(ngram_config);
+    if trainer.config.fractional {
+        trainer.load_lattices(aligner)?;
+    } else {
+        trainer.load_aligner(aligner)?;
+    }
+    trainer.train()
+}
+
+fn check_configs(align_config: &AlignerConfig, ngram_config: &NGramConfig) -> Result<()> {
+    if align_config.seq1_sep != ngram_config.seq1_sep
+        || align_config.seq2_sep != ngram_config.seq2_sep
+        || align_config.s1s2_sep != ngram_config.s1s2_sep
+        || align_config.skip != ngram_config.skip
+    {
+        return Err(anyhow!(
+            "Separators and skip token for alignment and training do not match"
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::train::smooth::Method;
+    use std::fs;
+    use tempfile::NamedTempFile;
+
+    fn align_config() -> AlignerConfig {
+        AlignerConfig {
+            seq1_max: 2,
+            seq2_max: 2,
+            seq1_del: false,
+            seq2_del: true,
+            restrict: true,
+            seq1_sep: "|".to_owned(),
+            seq2_sep: "|".to_owned(),
+            s1s2_sep: "}".to_owned(),
+            eps: "<eps>".to_owned(),
+            skip: "_".to_owned(),
+            s1_char_delim: String::new(),
+            s2_char_delim: " ".to_owned(),
+            penalize: false,
+            jobs: 1,
+        }
+    }
+
+    /// The same model as aligning and training separately, recording
+    /// how it was trained
+    #[test]
+    fn dictionary() {
+        let file = NamedTempFile::new().unwrap();
+        fs::write(
+            file.path(),
+            "READ\tR EH D\nREAD\tR IY D\nRED\tR EH D\nBREAD\tB R EH D\n\
+             BEAD\tB IY D\nBED\tB EH D\nDEAR\tD IH R\nBEE\tB IY\n",
+        )
+        .unwrap();
+        let input = file.path().to_path_buf();
+        let ngram_config = NGramConfig {
+            order: 3,
+            method: Method::WittenBell,
+            ..NGramConfig::default()
+        };
+        let model =
+            train_dictionary(&input, align_config(), 3, None, ngram_config.clone()).unwrap();
+        let mut aligner = Aligner::new(align_config());
+        aligner.load_dictionary(&input).unwrap();
+        aligner.em(3, None).unwrap();
+        let mut trainer = NGram::new(ngram_config.clone());
+        trainer.load_aligner(&aligner).unwrap();
+        assert_eq!(model.fst, trainer.train().unwrap());
+        let metadata = model.metadata.unwrap();
+        assert_eq!(
+            metadata,
+            Metadata {
+                data_hash: Some(hash_file(&input).unwrap()),
+                ..Metadata::from_configs(&align_config(), &ngram_config)
+            }
+        );
+        assert_eq!(metadata.seq2_del, Some(true));
+        assert_eq!(metadata.order, Some(3));
+        assert_eq!(metadata.method, Some(Method::WittenBell));
+        // Mismatched separators are caught before aligning
+        let ngram_config = NGramConfig {
+            skip: "-".to_owned(),
+            ..NGramConfig::default()
+        };
+        assert!(train_dictionary(&input, align_config(), 3, None, ngram_config).is_err());
+    }
+}
@@ -1,25 +1,63 @@
-use crate::StdVectorFst;
+use super::arpa::{read_arpa, write_arpa};
+use super::backoff::BackoffModel;
+use super::counts::NGramCounts;
+use super::smooth::{smooth, Method};
+use crate::align::Aligner;
+use crate::{LogVectorFst, StdVectorFst};
 use anyhow::{anyhow, Result};
 use rustfst::prelude::*;
-use rustfst::utils::acceptor;
+use rustfst::utils::{acceptor, decode_linear_fst};
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::prelude::*;
-use std::io::BufReader;
+use std::io::{BufReader, BufWriter};
 use std::path::PathBuf;
+use std::sync::Arc;
 
+#[derive(Debug, Clone)]
 pub struct Config {
     /// Order of N-Grams
     pub order: u8,
+    /// Smoothing method
+    pub method: Method,
+    /// Train on fractional counts from alignment lattices
+    pub fractional: bool,
+    /// Multi-token separator for input tokens
+    pub seq1_sep: String,
+    /// Multi-token separator for output tokens
+    pub seq2_sep: String,
+    /// Token used to separate input-output subsequences in the g2p model
+    pub s1s2_sep: String,
+    /// Skip token used to represent null transitions
+    pub skip: String,
     /// Write the output FSTs for debugging
     pub write_fsts: bool,
 }
 
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            order: 5,
+            method: Method::KneserNey,
+            fractional: false,
+            seq1_sep: "|".to_owned(),
+            seq2_sep: "|".to_owned(),
+            s1s2_sep: "}".to_owned(),
+            skip: "_".to_owned(),
+            write_fsts: false,
+        }
+    }
+}
+
 /// N-Gram trainer
 pub struct NGram {
     /// Configuration
     pub config: Config,
     /// Linear FSAs for each input sequence
     pub inputs: Vec<StdVectorFst>,
+    /// Alignment lattices for each input sequence, for fractional
+    /// counts
+    pub lattices: Vec<LogVectorFst>,
     /// Symbol table for input alignments
     pub syms: SymbolTable,
 }
@@ -27,52 +65,546 @@ pub struct NGram {
 impl NGram {
     pub fn new(config: Config) -> NGram {
         let inputs = Vec::<StdVectorFst>::new();
+        let lattices = Vec::<LogVectorFst>::new();
         let syms = SymbolTable::new();
         NGram {
             config,
             inputs,
+            lattices,
             syms,
         }
     }
 
     /// Read aligned inputs
+    ///
+    /// Each alignment may be followed by a TAB and its posterior
+    /// probability (as written by `Aligner::write_nbest_alignments`),
+    /// in which case it is counted fractionally.  If `fractional` is
+    /// set, all alignments must have posteriors, and the alternative
+    /// alignments of each entry, which end with an empty line, are
+    /// combined into a lattice.
     pub fn load_alignments(&mut self, input: &PathBuf) -> Result<()> {
         let fh = File::open(input)?;
         let reader = BufReader::new(fh);
-        // Where in the Rust documentation does it say that flatten()
-        // skips over None values?
-        for spam in reader.lines().flatten() {
-            let labels: Vec<Label> = spam
-                .trim()
+        let mut entry = Vec::<(Vec<Label>, f32)>::new();
+        for spam in reader.lines() {
+            let spam = spam?;
+            if spam.trim().is_empty() {
+                if self.config.fractional {
+                    self.add_entry_lattice(&entry)?;
+                    entry.clear();
+                }
+                continue;
+            }
+            let (alignment, posterior) = match spam.split_once('\t') {
+                Some((alignment, posterior)) => {
+                    let posterior: f32 = posterior
+                        .trim()
+                        .parse()
+                        .map_err(|_| anyhow!("Invalid posterior probability in: {}", spam))?;
+                    (alignment, Some(posterior))
+                }
+                None => (spam.as_str(), None),
+            };
+            let labels: Vec<Label> = alignment
                 .split_whitespace()
                 .map(|s| self.syms.add_symbol(s))
                 .collect();
-            // Will be topologically sorted, by definition
+            if !self.config.fractional {
+                let weight =
+                    posterior.map_or(TropicalWeight::one(), |p| TropicalWeight::new(-p.ln()));
+                // Will be topologically sorted, by definition
+                let fsa: StdVectorFst = acceptor(&labels, weight);
+                self.inputs.push(fsa);
+                continue;
+            }
+            let posterior = posterior.ok_or_else(|| {
+                anyhow!(
+                    "No posterior probability for fractional counts in: {}",
+                    spam
+                )
+            })?;
+            entry.push((labels, posterior));
+        }
+        self.add_entry_lattice(&entry)
+    }
+
+    /// Add a lattice for the alternative alignments of an entry,
+    /// weighted by their posteriors
+    fn add_entry_lattice(&mut self, entry: &[(Vec<Label>, f32)]) -> Result<()> {
+        if entry.is_empty() {
+            return Ok(());
+        }
+        // Entries that run together have too much mass
+        let mass: f32 = entry.iter().map(|&(_, p)| p).sum();
+        if mass > 1.0 + 1e-3 {
+            return Err(anyhow!(
+                "Posteriors of an entry add up to {} (entries must end with an empty line)",
+                mass
+            ));
+        }
+        let mut lattice = LogVectorFst::new();
+        let start = lattice.add_state();
+        let end = lattice.add_state();
+        lattice.set_start(start)?;
+        lattice.set_final(end, LogWeight::one())?;
+        for (labels, posterior) in entry {
+            let mut q = start;
+            for (i, &label) in labels.iter().enumerate() {
+                let next = if i + 1 == labels.len() {
+                    end
+                } else {
+                    lattice.add_state()
+                };
+                let weight = if i == 0 {
+                    LogWeight::new(-posterior.ln())
+                } else {
+                    LogWeight::one()
+                };
+                lattice.add_tr(q, Tr::new(label, label, weight, next))?;
+                q = next;
+            }
+        }
+        self.lattices.push(lattice);
+        Ok(())
+    }
+
+    /// Take the best alignments from an aligner
+    pub fn load_aligner(&mut self, aligner: &Aligner) -> Result<()> {
+        for alignment in aligner.alignments()? {
+            let labels: Vec<Label> = alignment
+                .iter()
+                .map(|(s1, s2)| {
+                    self.syms
+                        .add_symbol(s1.to_string() + &self.config.s1s2_sep + s2)
+                })
+                .collect();
             let fsa: StdVectorFst = acceptor(&labels, TropicalWeight::one());
             self.inputs.push(fsa);
         }
         Ok(())
     }
 
+    /// Take alignment lattices from an aligner, for fractional counts
+    pub fn load_lattices(&mut self, aligner: &Aligner) -> Result<()> {
+        let isyms = aligner.symbols();
+        for lattice in aligner.lattices() {
+            let mut lattice = lattice.clone();
+            for q in lattice.states_range() {
+                let mut trs = lattice.tr_iter_mut(q)?;
+                for idx in 0..trs.len() {
+                    let sym = isyms
+                        .get_symbol(trs[idx].ilabel)
+                        .ok_or_else(|| anyhow!("Undefined label {}", trs[idx].ilabel))?;
+                    let label = self.syms.add_symbol(sym);
+                    trs.set_ilabel(idx, label)?;
+                    trs.set_olabel(idx, label)?;
+                }
+            }
+            self.lattices.push(lattice);
+        }
+        Ok(())
+    }
+
+    /// Read a joint-sequence model in ARPA format and create fst
+    ///
+    /// The result is a backoff model like the one created by
+    /// `train` before conversion with `make_g2p_model`.
+    pub fn read_arpa(&mut self, input: &PathBuf) -> Result<StdVectorFst> {
+        let fh = File::open(input)?;
+        let model = read_arpa(BufReader::new(fh), &mut self.syms)?;
+        self.backoff_to_fst(&model)
+    }
+
+    /// Write a joint-sequence model (as created by `train_joint`
+    /// or `read_arpa`) in ARPA format
+    pub fn write_arpa(&self, model: &StdVectorFst, output: &PathBuf) -> Result<()> {
+        let syms = model
+            .input_symbols()
+            .ok_or_else(|| anyhow!("No symbol table in joint model"))?;
+        let model = BackoffModel::from_fst(model)?;
+        let fh = File::create(output)?;
+        let mut writer = BufWriter::new(fh);
+        write_arpa(&model, syms, &mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
     /// Count N-Grams and create fst
-    pub fn get_ngram_counts(&self, alignments: &Vec<StdVectorFst>) -> Result<StdVectorFst> {
-        let counts = StdVectorFst::new();
+    ///
+    /// The result has the same structure as the output of OpenGRM's
+    /// `ngramcount`: one state per history (including the `<s>`
+    /// history, which is the start state), transitions weighted with
+    /// the negative log count of each N-Gram, final weights holding
+    /// the counts of `</s>`, and epsilon transitions from each
+    /// history to its longest proper suffix.
+    pub fn get_ngram_counts(&self, alignments: &[StdVectorFst]) -> Result<StdVectorFst> {
+        let counts = self.count_alignments(alignments)?;
+        self.backoff_to_fst(&counts.to_model())
+    }
+
+    fn count_alignments(&self, alignments: &[StdVectorFst]) -> Result<NGramCounts> {
+        let mut counts = NGramCounts::new(self.config.order);
+        for fsa in alignments {
+            let path = decode_linear_fst(fsa)?;
+            // Weighted paths count fractionally (this is a no-op for
+            // the output of load_alignments)
+            let count = (-*path.weight.value() as f64).exp();
+            counts.add_sentence(&path.ilabels, count);
+        }
+        Ok(counts)
+    }
+
+    /// Count N-Grams in alignment lattices and create fst
+    ///
+    /// This is like `get_ngram_counts`, except that the counts are
+    /// the expected counts over all alignments.
+    pub fn get_fractional_counts(&self, lattices: &[LogVectorFst]) -> Result<StdVectorFst> {
+        let counts = self.count_lattices(lattices)?;
+        self.backoff_to_fst(&counts.to_model())
+    }
+
+    fn count_lattices(&self, lattices: &[LogVectorFst]) -> Result<NGramCounts> {
+        let mut counts = NGramCounts::new(self.config.order);
+        for lattice in lattices {
+            counts.add_lattice(lattice)?;
+        }
         Ok(counts)
     }
 
-    /// Make modified Kneser-Ney model
-    pub fn make_kn_model(&self, model: &StdVectorFst) -> Result<StdVectorFst> {
-        let model = StdVectorFst::new();
+    fn backoff_to_fst(&self, model: &BackoffModel) -> Result<StdVectorFst> {
+        let mut fst = model.to_fst()?;
+        let syms = Arc::new(self.syms.clone());
+        fst.set_input_symbols(Arc::clone(&syms));
+        fst.set_output_symbols(syms);
+        Ok(fst)
+    }
+
+    /// Make a smoothed model using the configured method
+    ///
+    /// The result has the same structure as the count FST, with
+    /// probabilities on the N-Gram transitions and backoff weights
+    /// on the epsilon transitions.
+    pub fn make_model(&self, counts: &StdVectorFst) -> Result<StdVectorFst> {
+        match self.config.method {
+            Method::KneserNey => self.make_kn_model(counts),
+            method => smoothed_model(counts, method),
+        }
+    }
+
+    /// Make a modified Kneser-Ney model, whatever the configured
+    /// method, as `ngrammake --method=kneser_ney` does
+    pub fn make_kn_model(&self, counts: &StdVectorFst) -> Result<StdVectorFst> {
+        smoothed_model(counts, Method::KneserNey)
+    }
+
+    /// Convert a joint-sequence model to a G2P transducer
+    ///
+    /// Each joint `g}p` label is split on `s1s2_sep` into an input
+    /// label for `g` and an output label for `p`, with a skip on the
+    /// input side becoming epsilon.  (`phonetisaurus-arpa2wfst` keeps
+    /// the skip as an input symbol instead, which `G2P` relabels to
+    /// epsilon when loading the model.)  The input and output symbol tables have the multi-token
+    /// separator (which `G2P` uses to find clusters) at label 1, and
+    /// contain all of the individual tokens in each cluster.
+    pub fn make_g2p_model(&self, model: &StdVectorFst) -> Result<StdVectorFst> {
+        let syms = model
+            .input_symbols()
+            .ok_or_else(|| anyhow!("No symbol table in joint model"))?;
+        let mut isyms = SymbolTable::new();
+        isyms.add_symbol(&self.config.seq1_sep);
+        let mut osyms = SymbolTable::new();
+        osyms.add_symbol(&self.config.seq2_sep);
+        let mut labels = HashMap::<Label, (Label, Label)>::new();
+        for (label, sym) in syms.iter() {
+            if label == EPS_LABEL {
+                continue;
+            }
+            let (s1, s2) = sym
+                .split_once(&self.config.s1s2_sep)
+                .ok_or_else(|| anyhow!("Symbol {} is not an input-output pair", sym))?;
+            let ilabel = if s1 == self.config.skip {
+                EPS_LABEL
+            } else {
+                add_cluster(&mut isyms, s1, &self.config.seq1_sep)
+            };
+            let olabel = add_cluster(&mut osyms, s2, &self.config.seq2_sep);
+            labels.insert(label, (ilabel, olabel));
+        }
+        let mut fst = StdVectorFst::new();
+        fst.add_states(model.num_states());
+        if let Some(start) = model.start() {
+            fst.set_start(start)?;
+        }
+        for q in model.states_iter() {
+            for tr in model.get_trs(q)?.trs() {
+                let (ilabel, olabel) = if tr.ilabel == EPS_LABEL {
+                    (EPS_LABEL, EPS_LABEL)
+                } else {
+                    *labels
+                        .get(&tr.ilabel)
+                        .ok_or_else(|| anyhow!("Label {} not found in symbol table", tr.ilabel))?
+                };
+                fst.add_tr(q, Tr::new(ilabel, olabel, tr.weight, tr.nextstate))?;
+            }
+            if let Some(weight) = model.final_weight(q)? {
+                fst.set_final(q, weight)?;
+            }
+        }
+        fst.set_input_symbols(Arc::new(isyms));
+        fst.set_output_symbols(Arc::new(osyms));
+        Ok(fst)
+    }
+
+    /// Train a joint-sequence N-Gram model
+    pub fn train_joint(&self) -> Result<StdVectorFst> {
+        let model = if self.config.fractional {
+            if self.config.method != Method::KneserNey {
+                return Err(anyhow!(
+                    "Fractional counts are only supported with Kneser-Ney smoothing"
+                ));
+            }
+            // Keep the fractional counts, which the count FST cannot hold
+            let mut model = self.count_lattices(&self.lattices)?.to_model();
+            if self.config.write_fsts {
+                self.backoff_to_fst(&model)?.write("counts.fst")?;
+            }
+            smooth(&mut model, self.config.method)?;
+            self.backoff_to_fst(&model)?
+        } else {
+            let counts = self.get_ngram_counts(&self.inputs)?;
+            if self.config.write_fsts {
+                counts.write("counts.fst")?;
+            }
+            self.make_model(&counts)?
+        };
+        if self.config.write_fsts {
+            model.write("joint.fst")?;
+        }
         Ok(model)
     }
 
     /// Train an N-Gram model and convert to fst
     pub fn train(&self) -> Result<StdVectorFst> {
-        // Collect counts
-        let counts = self.get_ngram_counts(&self.inputs)?;
-        // Create model
-        let model = self.make_kn_model(&counts)?;
+        let model = self.train_joint()?;
+        // Convert it to a transducer
+        self.make_g2p_model(&model)
+    }
+}
 
-        Ok(model)
+/// Add a (possibly multi-token) cluster to a symbol table along with
+/// its individual tokens, returning its label
+fn add_cluster(syms: &mut SymbolTable, cluster: &str, sep: &str) -> Label {
+    for token in cluster.split(sep) {
+        syms.add_symbol(token);
+    }
+    syms.add_symbol(cluster)
+}
+
+/// Smooth a count FST into a model with the same structure
+fn smoothed_model(counts: &StdVectorFst, method: Method) -> Result<StdVectorFst> {
+    let mut model = BackoffModel::from_fst(counts)?;
+    model.round_counts();
+    smooth(&mut model, method)?;
+    let mut fst = model.to_fst()?;
+    fst.set_symts_from_fst(counts);
+    Ok(fst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::g2p::{Config as G2PConfig, G2P};
+
+    /// Bigram counts of `a b` and `a`, with the `<s>` history as the
+    /// start state, `</s>` as final weights, and backoff transitions
+    /// to the unigram state
+    #[test]
+    fn ngram_counts() {
+        let config = Config {
+            order: 2,
+            ..Config::default()
+        };
+        let mut ngram = NGram::new(config);
+        let a = ngram.syms.add_symbol("a");
+        let b = ngram.syms.add_symbol("b");
+        let inputs: Vec<StdVectorFst> = [vec![a, b], vec![a]]
+            .iter()
+            .map(|labels| acceptor(labels, TropicalWeight::one()))
+            .collect();
+        let fst = ngram.get_ngram_counts(&inputs).unwrap();
+        // States are created for <s>, the unigram history, a and b
+        assert_eq!(fst.start(), Some(0));
+        assert_eq!(fst.num_states(), 4);
+        let count = |n: f32| -n.ln();
+        let mut trs = Vec::new();
+        for q in fst.states_iter() {
+            for tr in fst.get_trs(q).unwrap().trs() {
+                assert_eq!(tr.ilabel, tr.olabel);
+                trs.push((q, tr.ilabel, *tr.weight.value(), tr.nextstate));
+            }
+        }
+        assert_eq!(
+            trs,
+            [
+                (0, a, count(2.0), 2),
+                (0, EPS_LABEL, f32::INFINITY, 1),
+                (1, a, count(2.0), 2),
+                (1, b, count(1.0), 3),
+                (2, b, count(1.0), 3),
+                (2, EPS_LABEL, f32::INFINITY, 1),
+                (3, EPS_LABEL, f32::INFINITY, 1),
+            ]
+        );
+        let finals: Vec<Option<f32>> = fst
+            .states_iter()
+            .map(|q| fst.final_weight(q).unwrap().map(|w| *w.value()))
+            .collect();
+        assert_eq!(
+            finals,
+            [None, Some(count(2.0)), Some(count(1.0)), Some(count(1.0))]
+        );
+        assert_eq!(fst.input_symbols().unwrap().get_label("b"), Some(b));
+    }
+
+    /// Joint model with a cluster, an insertion and a deletion, and a
+    /// backoff transition
+    fn joint_model() -> StdVectorFst {
+        let syms = symt!["a}EY", "_}AH", "b|c}K|S", "x}_"];
+        let label = |sym| syms.get_label(sym).unwrap();
+        let mut fst = StdVectorFst::new();
+        let (q0, q1) = (fst.add_state(), fst.add_state());
+        fst.set_start(q0).unwrap();
+        fst.emplace_tr(q0, label("a}EY"), label("a}EY"), 1.0, q1)
+            .unwrap();
+        fst.emplace_tr(q0, label("b|c}K|S"), label("b|c}K|S"), 2.0, q1)
+            .unwrap();
+        fst.emplace_tr(q1, label("_}AH"), label("_}AH"), 0.5, q1)
+            .unwrap();
+        fst.emplace_tr(q1, label("x}_"), label("x}_"), 1.5, q1)
+            .unwrap();
+        fst.emplace_tr(q1, EPS_LABEL, EPS_LABEL, 0.25, q0).unwrap();
+        fst.set_final(q1, 0.0).unwrap();
+        let syms = Arc::new(syms);
+        fst.set_input_symbols(Arc::clone(&syms));
+        fst.set_output_symbols(syms);
+        fst
+    }
+
+    #[test]
+    fn g2p_model_labels() {
+        let ngram = NGram::new(Config::default());
+        let fst = ngram.make_g2p_model(&joint_model()).unwrap();
+        let isyms = fst.input_symbols().unwrap();
+        let osyms = fst.output_symbols().unwrap();
+        // The separator at label 1, then each token before its cluster
+        let symbols = |syms: &SymbolTable| -> Vec<String> {
+            syms.iter().map(|(_, sym)| sym.to_owned()).collect()
+        };
+        assert_eq!(symbols(isyms), ["<eps>", "|", "a", "b", "c", "b|c", "x"]);
+        assert_eq!(
+            symbols(osyms),
+            ["<eps>", "|", "EY", "AH", "K", "S", "K|S", "_"]
+        );
+        let mut trs = Vec::new();
+        for q in fst.states_iter() {
+            for tr in fst.get_trs(q).unwrap().trs() {
+                trs.push((
+                    q,
+                    isyms.get_symbol(tr.ilabel).unwrap(),
+                    osyms.get_symbol(tr.olabel).unwrap(),
+                    *tr.weight.value(),
+                    tr.nextstate,
+                ));
+            }
+        }
+        // Skips on the input side become epsilon, and backoff
+        // transitions stay epsilon on both sides
+        assert_eq!(
+            trs,
+            [
+                (0, "a", "EY", 1.0, 1),
+                (0, "b|c", "K|S", 2.0, 1),
+                (1, "<eps>", "AH", 0.5, 1),
+                (1, "x", "_", 1.5, 1),
+                (1, "<eps>", "<eps>", 0.25, 0),
+            ]
+        );
+        assert_eq!(fst.start(), Some(0));
+        assert_eq!(fst.final_weight(1).unwrap(), Some(TropicalWeight::one()));
+        assert_eq!(fst.final_weight(0).unwrap(), None);
+    }
+
+    /// `phonetisaurus-arpa2wfst` keeps the skip as an input symbol,
+    /// which `G2P` relabels to epsilon, so its models decode the same
+    /// as ours
+    #[test]
+    fn arpa2wfst_skip() {
+        let ngram = NGram::new(Config::default());
+        let ours = ngram.make_g2p_model(&joint_model()).unwrap();
+        let mut theirs = ours.clone();
+        let mut isyms = SymbolTable::clone(ours.input_symbols().unwrap());
+        let skip = isyms.add_symbol("_");
+        for q in ours.states_iter() {
+            theirs.delete_trs(q).unwrap();
+            for tr in ours.get_trs(q).unwrap().trs() {
+                let mut tr = tr.clone();
+                if tr.ilabel == EPS_LABEL && tr.olabel != EPS_LABEL {
+                    tr.ilabel = skip;
+                }
+                theirs.add_tr(q, tr).unwrap();
+            }
+        }
+        theirs.set_input_symbols(Arc::new(isyms));
+        let decode = |fst: StdVectorFst| {
+            let g2p = G2P::new(G2PConfig::default(), fst.into()).unwrap();
+            g2p.g2p_nbest("abc", 4)
+                .unwrap()
+                .into_iter()
+                .map(|(pron, score)| (pron.join(" "), score))
+                .collect::<Vec<_>>()
+        };
+        let prons = decode(ours);
+        assert_eq!(prons.len(), 4);
+        assert_eq!(decode(theirs), prons);
+    }
+
+    /// Consecutive entries for the same spelling are kept apart even
+    /// when their posteriors would fit in one
+    #[test]
+    fn nbest_entries() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(
+            file.path(),
+            "r}R e}EH a}_ d}D\t0.5\nr}R e}IY a}_ d}D\t0.2\n\n\
+             r}R e}EH a}_ d}D\t0.2\n\n",
+        )
+        .unwrap();
+        let path = file.path().to_path_buf();
+        let config = Config {
+            fractional: true,
+            ..Config::default()
+        };
+        let mut ngram = NGram::new(config);
+        ngram.load_alignments(&path).unwrap();
+        assert_eq!(ngram.lattices.len(), 2);
+        assert!(ngram.inputs.is_empty());
+        // Without the fractional flag, every alignment is an input
+        let mut ngram = NGram::new(Config::default());
+        ngram.load_alignments(&path).unwrap();
+        assert_eq!(ngram.inputs.len(), 3);
+        // Without separators, entries run together
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(
+            file.path(),
+            "r}R e}EH a}_ d}D\t0.8\nr}R e}IY a}_ d}D\t0.2\nr}R e}EH a}_ d}D\t0.5\n",
+        )
+        .unwrap();
+        let config = Config {
+            fractional: true,
+            ..Config::default()
+        };
+        let mut ngram = NGram::new(config);
+        assert!(ngram.load_alignments(&file.path().to_path_buf()).is_err());
     }
 }
@@ -0,0 +1,575 @@
+use super::backoff::{BackoffModel, BackoffState, FractionalCount};
+use anyhow::{anyhow, Result};
+use rustfst::prelude::*;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Smoothing methods for N-Gram models, with the same names as in
+/// OpenGRM's `ngrammake`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    /// Interpolated modified Kneser-Ney
+    KneserNey,
+    /// Interpolated Witten-Bell
+    WittenBell,
+    /// Interpolated absolute discounting
+    Absolute,
+    /// Katz backoff with Good-Turing discounting
+    Katz,
+    /// Maximum likelihood estimation
+    Unsmoothed,
+}
+
+impl FromStr for Method {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Method> {
+        match s {
+            "kneser_ney" => Ok(Method::KneserNey),
+            "witten_bell" => Ok(Method::WittenBell),
+            "absolute" => Ok(Method::Absolute),
+            "katz" => Ok(Method::Katz),
+            "unsmoothed" => Ok(Method::Unsmoothed),
+            _ => Err(anyhow!("Unknown smoothing method {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Method::KneserNey => "kneser_ney",
+            Method::WittenBell => "witten_bell",
+            Method::Absolute => "absolute",
+            Method::Katz => "katz",
+            Method::Unsmoothed => "unsmoothed",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Replace counts in a backoff model with smoothed probabilities
+/// and backoff weights.
+pub(crate) fn smooth(model: &mut BackoffModel, method: Method) -> Result<()> {
+    match method {
+        Method::KneserNey => kneser_ney(model),
+        Method::WittenBell => witten_bell(model),
+        Method::Absolute => absolute(model),
+        Method::Katz => katz(model),
+        Method::Unsmoothed => unsmoothed(model),
+    }
+}
+
+/// Interpolated modified Kneser-Ney, with three discounts per order
+/// (for counts of 1, 2, and 3 or more) estimated from the
+/// count-of-counts as in Chen & Goodman (1998).  If the model has
+/// fractional counts, the discounts and continuation counts are
+/// their expected values as in Zhang & Chiang (2014).
+fn kneser_ney(model: &mut BackoffModel) -> Result<()> {
+    continuation_counts(model);
+    let discounts: Vec<[f64; 3]> = kn_count_of_counts(model)
+        .iter()
+        .map(|n| kn_discounts(n))
+        .collect();
+    interpolate(model, |state| {
+        let d = &discounts[state.depth];
+        state
+            .fractional
+            .iter()
+            .map(|count| {
+                let p = &count.probs;
+                let three_or_more = 1.0 - p[0] - p[1] - p[2];
+                (d[0] * p[1] + d[1] * p[2] + d[2] * three_or_more).min(count.expected)
+            })
+            .collect()
+    })
+}
+
+/// Replace lower-order counts in a Kneser-Ney model with
+/// continuation counts
+fn continuation_counts(model: &mut BackoffModel) {
+    // Otherwise, counts are (more or less) certain
+    for state in model.states.iter_mut() {
+        if state.fractional.len() != state.ngrams.len() {
+            state.fractional = state
+                .ngrams
+                .iter()
+                .map(|&(_, c, _)| FractionalCount::from_expected(c))
+                .collect();
+        }
+    }
+    // Lower-order counts are replaced by the number of distinct
+    // contexts in which they occur, i.e. the number of higher-order
+    // states backing off to them which contain the same N-Gram.
+    // States nothing backs off to (the highest order, and histories
+    // starting with <s>) keep their counts.
+    let mut contexts: Vec<HashMap<Label, FractionalCount>> =
+        vec![HashMap::new(); model.states.len()];
+    for state in &model.states {
+        if let Some(backoff) = state.backoff {
+            for (&(label, _, _), count) in state.ngrams.iter().zip(&state.fractional) {
+                contexts[backoff as usize]
+                    .entry(label)
+                    .or_insert_with(FractionalCount::zero)
+                    .add(count.seen());
+            }
+        }
+    }
+    for (state, contexts) in model.states.iter_mut().zip(contexts) {
+        if contexts.is_empty() {
+            continue;
+        }
+        for ((label, count, _), fractional) in
+            state.ngrams.iter_mut().zip(state.fractional.iter_mut())
+        {
+            if let Some(&n) = contexts.get(label) {
+                *count = n.expected;
+                *fractional = n;
+            }
+        }
+    }
+}
+
+/// Count-of-counts for Kneser-Ney (`n[0]` is the number of N-Grams
+/// seen once, etc, up to 4) for each order, indexed by history
+/// length.  These are expected values over the fractional counts.
+fn kn_count_of_counts(model: &BackoffModel) -> Vec<Vec<f64>> {
+    let max_depth = model.states.iter().map(|s| s.depth).max().unwrap_or(0);
+    let mut n = vec![vec![0.0; 4]; max_depth + 1];
+    for state in &model.states {
+        for count in &state.fractional {
+            for (r, p) in count.probs[1..].iter().enumerate() {
+                n[state.depth][r] += p;
+            }
+        }
+    }
+    n
+}
+
+/// Compute modified Kneser-Ney discounts from counts-of-counts
+/// (`n[0]` is the number of N-Grams seen once, etc)
+fn kn_discounts(n: &[f64]) -> [f64; 3] {
+    let y = n[0] / (n[0] + 2.0 * n[1]);
+    let discounts = [
+        1.0 - 2.0 * y * n[1] / n[0],
+        2.0 - 3.0 * y * n[2] / n[1],
+        3.0 - 4.0 * y * n[3] / n[2],
+    ];
+    // With very little data, some of these are undefined or
+    // nonsensical, so fall back to absolute discounting
+    if discounts.iter().all(|d| d.is_finite() && *d > 0.0) {
+        discounts
+    } else {
+        [absolute_discount(n); 3]
+    }
+}
+
+/// Interpolated absolute discounting, with one discount per order
+/// estimated from the count-of-counts as in Ney et al. (1994).
+fn absolute(model: &mut BackoffModel) -> Result<()> {
+    let discounts: Vec<f64> = count_of_counts(model, 2)
+        .iter()
+        .map(|n| absolute_discount(n))
+        .collect();
+    interpolate(model, |state| {
+        let discount = discounts[state.depth];
+        state
+            .ngrams
+            .iter()
+            .map(|&(_, c, _)| discount.min(c).max(0.0))
+            .collect()
+    })
+}
+
+/// Compute the absolute discount from counts-of-counts
+fn absolute_discount(n: &[f64]) -> f64 {
+    let d = n[0] / (n[0] + 2.0 * n[1]);
+    if d.is_finite() && d > 0.0 {
+        d
+    } else {
+        0.5
+    }
+}
+
+/// Interpolated Witten-Bell, where the weight of the lower-order
+/// model depends on the number of distinct tokens following each
+/// history.
+fn witten_bell(model: &mut BackoffModel) -> Result<()> {
+    interpolate(model, |state| {
+        let types = state.ngrams.iter().filter(|&&(_, c, _)| c > 0.0).count() as f64;
+        let total: f64 = state.ngrams.iter().map(|&(_, c, _)| c).sum();
+        state
+            .ngrams
+            .iter()
+            .map(|&(_, c, _)| c * types / (total + types))
+            .collect()
+    })
+}
+
+/// Highest count discounted by Katz backoff
+const KATZ_MAX_COUNT: usize = 5;
+
+/// Katz backoff with Good-Turing discounting of counts up to
+/// `KATZ_MAX_COUNT`, as in Katz (1987).
+fn katz(model: &mut BackoffModel) -> Result<()> {
+    let ratios: Vec<[f64; KATZ_MAX_COUNT]> = count_of_counts(model, KATZ_MAX_COUNT + 1)
+        .iter()
+        .map(|n| katz_ratios(n))
+        .collect();
+    for q in by_depth(model) {
+        let state = &model.states[q];
+        let total: f64 = state.ngrams.iter().map(|&(_, c, _)| c).sum();
+        let backoff = match state.backoff {
+            None => {
+                normalize(&mut model.states[q]);
+                continue;
+            }
+            Some(backoff) => backoff,
+        };
+        let ratios = &ratios[state.depth];
+        let mut probs = Vec::with_capacity(state.ngrams.len());
+        let mut seen_mass = 0.0;
+        let mut lower_mass = 0.0;
+        for &(label, count, nextstate) in &state.ngrams {
+            let ratio = if count > 0.0 && count <= KATZ_MAX_COUNT as f64 {
+                ratios[count.ceil() as usize - 1]
+            } else {
+                1.0
+            };
+            let prob = if total > 0.0 {
+                ratio * count / total
+            } else {
+                0.0
+            };
+            seen_mass += prob;
+            lower_mass += model.backoff_value(backoff, label)?;
+            probs.push((label, prob, nextstate));
+        }
+        // Backoff weight normalizes the lower-order distribution over
+        // the tokens not seen in this history, unless there are none,
+        // in which case the discounted mass has nowhere to go
+        let alpha = if lower_mass < 1.0 - 1e-9 {
+            ((1.0 - seen_mass) / (1.0 - lower_mass)).max(0.0)
+        } else {
+            for (_, prob, _) in probs.iter_mut() {
+                *prob /= seen_mass;
+            }
+            0.0
+        };
+        let state = &mut model.states[q];
+        state.ngrams = probs;
+        state.backoff_weight = alpha;
+    }
+    Ok(())
+}
+
+/// Compute the Katz discount ratios for counts of 1 to
+/// `KATZ_MAX_COUNT` from counts-of-counts up to `KATZ_MAX_COUNT + 1`
+fn katz_ratios(n: &[f64]) -> [f64; KATZ_MAX_COUNT] {
+    let mut ratios = [1.0; KATZ_MAX_COUNT];
+    let common = (KATZ_MAX_COUNT + 1) as f64 * n[KATZ_MAX_COUNT] / n[0];
+    for (r, ratio) in ratios.iter_mut().enumerate() {
+        let c = (r + 1) as f64;
+        let gt = (c + 1.0) * n[r + 1] / (c * n[r]);
+        let d = (gt - common) / (1.0 - common);
+        // Do not discount where Good-Turing is undefined
+        if d.is_finite() && d > 0.0 && d <= 1.0 {
+            *ratio = d;
+        }
+    }
+    ratios
+}
+
+/// Maximum likelihood estimation.  Backoff transitions are kept with
+/// a weight of one (as in an ARPA file with no backoff weights) so
+/// that the model can still be used for unseen sequences, which
+/// means it is not normalized.
+fn unsmoothed(model: &mut BackoffModel) -> Result<()> {
+    for state in model.states.iter_mut() {
+        normalize(state);
+        state.backoff_weight = 1.0;
+    }
+    Ok(())
+}
+
+/// Estimate an interpolated model, given a function returning the
+/// amount discounted from each count in a given state.  The
+/// discounted mass becomes the weight of the lower-order model,
+/// which is also the backoff weight.  Unigrams are not discounted.
+fn interpolate<F>(model: &mut BackoffModel, discount: F) -> Result<()>
+where
+    F: Fn(&BackoffState) -> Vec<f64>,
+{
+    // Estimate probabilities from lower to higher orders, since each
+    // order is interpolated with the one below
+    for q in by_depth(model) {
+        let state = &model.states[q];
+        let backoff = match state.backoff {
+            None => {
+                normalize(&mut model.states[q]);
+                continue;
+            }
+            Some(backoff) => backoff,
+        };
+        let total: f64 = state.ngrams.iter().map(|&(_, c, _)| c).sum();
+        let discounts = discount(state);
+        let gamma = if total > 0.0 {
+            discounts.iter().sum::<f64>() / total
+        } else {
+            1.0
+        };
+        let mut probs = Vec::with_capacity(state.ngrams.len());
+        for (&(label, count, nextstate), d) in state.ngrams.iter().zip(discounts) {
+            let lower = model.backoff_value(backoff, label)?;
+            let prob = if total > 0.0 {
+                (count - d) / total + gamma * lower
+            } else {
+                lower
+            };
+            probs.push((label, prob, nextstate));
+        }
+        let state = &mut model.states[q];
+        state.ngrams = probs;
+        state.backoff_weight = gamma;
+    }
+    Ok(())
+}
+
+/// Replace counts in a state with relative frequencies
+fn normalize(state: &mut BackoffState) {
+    let total: f64 = state.ngrams.iter().map(|&(_, c, _)| c).sum();
+    if total > 0.0 {
+        for (_, count, _) in state.ngrams.iter_mut() {
+            *count /= total;
+        }
+    }
+}
+
+/// State indices sorted by increasing history length
+fn by_depth(model: &BackoffModel) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..model.states.len()).collect();
+    order.sort_by_key(|&q| model.states[q].depth);
+    order
+}
+
+/// Count-of-counts (`n[0]` is the number of N-Grams seen once, etc,
+/// up to `max_count`) for each order, indexed by history length.
+/// Non-integer counts are ignored.
+fn count_of_counts(model: &BackoffModel, max_count: usize) -> Vec<Vec<f64>> {
+    let max_depth = model.states.iter().map(|s| s.depth).max().unwrap_or(0);
+    let mut n = vec![vec![0.0; max_count]; max_depth + 1];
+    for state in &model.states {
+        for &(_, count, _) in &state.ngrams {
+            let r = count.round();
+            if (count - r).abs() < 1e-6 && r >= 1.0 && r <= max_count as f64 {
+                n[state.depth][r as usize - 1] += 1.0;
+            }
+        }
+    }
+    n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::train::counts::NGramCounts;
+
+    const A: Label = 1;
+    const B: Label = 2;
+    const C: Label = 3;
+
+    /// Bigram counts for a tiny corpus, which has (with `<s>` and
+    /// `</s>`) the bigrams `<s> a` 6 times, `a b` and `c </s>` 3
+    /// times, `b </s>`, `b c` and `a </s>` twice, and `<s> b`, `a c`
+    /// and `c c` once
+    fn corpus() -> BackoffModel {
+        let mut counts = NGramCounts::new(2);
+        let sentences: [&[Label]; 7] = [
+            &[A, B],
+            &[A, B],
+            &[A, B, C],
+            &[A, C, C],
+            &[A],
+            &[A],
+            &[B, C],
+        ];
+        for sentence in sentences {
+            counts.add_sentence(sentence, 1.0);
+        }
+        counts.to_model()
+    }
+
+    fn unigram_state(model: &BackoffModel) -> StateId {
+        model
+            .states
+            .iter()
+            .position(|s| s.backoff.is_none())
+            .unwrap() as StateId
+    }
+
+    /// State for the history of a single token
+    fn history(model: &BackoffModel, label: Label) -> StateId {
+        let unigram = &model.states[unigram_state(model) as usize];
+        let idx = unigram
+            .ngrams
+            .binary_search_by_key(&label, |&(l, _, _)| l)
+            .unwrap();
+        unigram.ngrams[idx].2
+    }
+
+    /// Check that the probabilities of all tokens (including `</s>`)
+    /// after each history add up to one
+    fn assert_normalized(model: &BackoffModel) {
+        let unigram = &model.states[unigram_state(model) as usize];
+        for q in 0..model.states.len() {
+            let total: f64 = unigram
+                .ngrams
+                .iter()
+                .map(|&(label, _, _)| model.backoff_value(q as StateId, label).unwrap())
+                .sum();
+            assert!((total - 1.0).abs() < 1e-9, "State {} sums to {}", q, total);
+        }
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{} != {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn kn_count_of_counts_and_discounts() {
+        let mut model = corpus();
+        continuation_counts(&mut model);
+        // Unigrams are counted by distinct contexts: a 1, b 2, c 3
+        // and </s> 3
+        let n = kn_count_of_counts(&model);
+        assert_eq!(n, vec![vec![1.0, 1.0, 2.0, 0.0], vec![3.0, 3.0, 2.0, 0.0]]);
+        // Y = 3 / (3 + 2 * 3) = 1/3, D1 = 1 - 2Y * 3/3, D2 = 2 - 3Y *
+        // 2/3, D3+ = 3 - 4Y * 0/2
+        let d = kn_discounts(&n[1]);
+        assert_close(d[0], 1.0 / 3.0);
+        assert_close(d[1], 4.0 / 3.0);
+        assert_close(d[2], 3.0);
+        // D2 = 2 - 3Y * 2/1 = 0, so fall back to absolute discounting
+        assert_eq!(kn_discounts(&n[0]), [1.0 / 3.0; 3]);
+    }
+
+    #[test]
+    fn kn_normalized() {
+        let mut model = corpus();
+        kneser_ney(&mut model).unwrap();
+        assert_normalized(&model);
+        // Both bigrams after b were seen twice, so D2 is taken from
+        // each and the lower order gets 2/3 of the mass, with P(c) =
+        // 3/9 from the continuation counts
+        let b = history(&model, B);
+        assert_close(
+            model.backoff_value(b, C).unwrap(),
+            (2.0 - 4.0 / 3.0) / 4.0 + 2.0 / 9.0,
+        );
+        assert_close(model.states[b as usize].backoff_weight, 2.0 / 3.0);
+    }
+
+    #[test]
+    fn witten_bell_normalized() {
+        let mut model = corpus();
+        witten_bell(&mut model).unwrap();
+        assert_normalized(&model);
+        // Two types and four tokens after b
+        let b = history(&model, B);
+        assert_close(model.states[b as usize].backoff_weight, 2.0 / 6.0);
+    }
+
+    #[test]
+    fn absolute_normalized() {
+        let mut model = corpus();
+        absolute(&mut model).unwrap();
+        assert_normalized(&model);
+        // D = 3 / (3 + 2 * 3) for bigrams, taken from both after b
+        let b = history(&model, B);
+        assert_close(model.states[b as usize].backoff_weight, 2.0 / 3.0 / 4.0);
+    }
+
+    #[test]
+    fn katz_normalized() {
+        let mut model = corpus();
+        katz(&mut model).unwrap();
+        assert_normalized(&model);
+    }
+
+    #[test]
+    fn unsmoothed_relative_frequencies() {
+        let mut model = corpus();
+        unsmoothed(&mut model).unwrap();
+        for state in &model.states {
+            let total: f64 = state.ngrams.iter().map(|&(_, p, _)| p).sum();
+            assert_close(total, 1.0);
+            assert_eq!(state.backoff_weight, 1.0);
+        }
+        assert_close(model.backoff_value(model.start, A).unwrap(), 6.0 / 7.0);
+    }
+
+    #[test]
+    fn katz_discount_ratios() {
+        let n = [20.0, 8.0, 5.0, 3.0, 2.0, 1.0];
+        // (r + 1) n[r + 1] / (r n[r]), corrected for 6 n[6] / n[1] =
+        // 0.3 of the mass
+        let expected = [0.8, 15.0 / 16.0, 0.8, 10.0 / 12.0, 0.6].map(|gt| (gt - 0.3) / 0.7);
+        for (actual, expected) in katz_ratios(&n).iter().zip(expected) {
+            assert_close(*actual, expected);
+        }
+        // Not discounted where Good-Turing would add to the count
+        assert_eq!(katz_ratios(&[3.0, 3.0, 2.0, 0.0, 0.0, 1.0]), [1.0; 5]);
+    }
+
+    /// A bigram state with the count-of-counts of `katz_discount_ratios`,
+    /// backing off to a uniform unigram state with more tokens
+    fn katz_model() -> BackoffModel {
+        let counts = [(1, 20), (2, 8), (3, 5), (4, 3), (5, 2), (6, 1)];
+        let mut ngrams = Vec::new();
+        for (count, n) in counts {
+            for _ in 0..n {
+                ngrams.push((ngrams.len() as Label + 1, count as f64, 0));
+            }
+        }
+        let unigrams = (1..=ngrams.len() as Label + 10)
+            .map(|l| (l, 1.0, 0))
+            .collect();
+        let state = |backoff, depth, ngrams| BackoffState {
+            backoff,
+            backoff_weight: 0.0,
+            depth,
+            ngrams,
+            fractional: Vec::new(),
+        };
+        BackoffModel {
+            start: 1,
+            states: vec![state(None, 0, unigrams), state(Some(0), 1, ngrams)],
+        }
+    }
+
+    #[test]
+    fn katz_max_count() {
+        let mut model = katz_model();
+        let ngrams = model.states[1].ngrams.clone();
+        let total: f64 = ngrams.iter().map(|&(_, c, _)| c).sum();
+        katz(&mut model).unwrap();
+        assert_normalized(&model);
+        let ratios = katz_ratios(&[20.0, 8.0, 5.0, 3.0, 2.0, 1.0]);
+        assert!(ratios[KATZ_MAX_COUNT - 1] < 1.0);
+        for (&(_, count, _), &(_, prob, _)) in ngrams.iter().zip(&model.states[1].ngrams) {
+            // Counts above KATZ_MAX_COUNT are not discounted
+            let ratio = if count > KATZ_MAX_COUNT as f64 {
+                1.0
+            } else {
+                ratios[count as usize - 1]
+            };
+            assert_close(prob, ratio * count / total);
+        }
+    }
+}
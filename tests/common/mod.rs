@@ -0,0 +1,43 @@
+//! Tiny hand-built models shared by the integration tests
+
+use rustfst::prelude::*;
+use std::sync::Arc;
+
+/// A transducer with the given input and output symbols (after
+/// epsilon), transitions as (state, input, output, weight, next
+/// state) and final weights, starting in state 0
+pub fn tiny_fst(
+    isyms: &[&str],
+    osyms: &[&str],
+    trs: &[(StateId, &str, &str, f32, StateId)],
+    finals: &[(StateId, f32)],
+) -> VectorFst<TropicalWeight> {
+    let symbols = |syms: &[&str]| {
+        let mut table = SymbolTable::new();
+        for &sym in syms {
+            table.add_symbol(sym);
+        }
+        table
+    };
+    let (isyms, osyms) = (symbols(isyms), symbols(osyms));
+    let label = |syms: &SymbolTable, sym| syms.get_label(sym).unwrap();
+    let nstates = trs
+        .iter()
+        .flat_map(|&(q, _, _, _, next)| [q, next])
+        .chain(finals.iter().map(|&(q, _)| q))
+        .max()
+        .map_or(1, |q| q as usize + 1);
+    let mut fst = VectorFst::<TropicalWeight>::new();
+    fst.add_states(nstates);
+    fst.set_start(0).unwrap();
+    for &(q, i, o, weight, next) in trs {
+        fst.emplace_tr(q, label(&isyms, i), label(&osyms, o), weight, next)
+            .unwrap();
+    }
+    for &(q, weight) in finals {
+        fst.set_final(q, weight).unwrap();
+    }
+    fst.set_input_symbols(Arc::new(isyms));
+    fst.set_output_symbols(Arc::new(osyms));
+    fst
+}
@@ -0,0 +1,258 @@
+//! N-best decoding, with its cutoffs, unknown graphemes, and decoding
+//! in parallel
+
+mod common;
+
+use common::tiny_fst;
+use rustfst_g2p::g2p::{Config as G2PConfig, OovPolicy, G2P};
+use rustfst_g2p::model::Model;
+use std::fs;
+use std::process::Command;
+use tempfile::{NamedTempFile, TempDir};
+
+/// A tiny model where several alignments of AB give the same phones,
+/// through skips and output clusters
+fn variants_model() -> Model {
+    let fst = tiny_fst(
+        &["|", "_", "A", "B", "A|B"],
+        &["|", "_", "EY", "AH", "B", "EY|B"],
+        &[
+            (0, "A", "EY", 1.0, 0),
+            (0, "A", "AH", 3.0, 0),
+            (0, "B", "B", 1.0, 0),
+            (0, "B", "_", 1.0, 0),
+            (0, "A|B", "EY|B", 1.5, 0),
+            (0, "A|B", "EY", 2.5, 0),
+        ],
+        &[(0, 0.0)],
+    );
+    Model::from(fst)
+}
+
+/// Alignments giving the same phones once skips are removed and
+/// clusters expanded are only output once, with the best score
+#[test]
+fn nbest_distinct() {
+    let g2p = G2P::new(G2PConfig::default(), variants_model()).unwrap();
+    assert_eq!(
+        g2p.g2p_nbest("AB", 10).unwrap(),
+        vec![
+            (vec!["EY", "B"], 1.5),
+            (vec!["EY"], 2.0),
+            (vec!["AH"], 4.0),
+            (vec!["AH", "B"], 4.0),
+        ]
+    );
+    assert_eq!(
+        g2p.g2p_nbest("AB", 2).unwrap(),
+        vec![(vec!["EY", "B"], 1.5), (vec!["EY"], 2.0)]
+    );
+    assert_eq!(g2p.g2p_nbest("AB", 0).unwrap(), vec![]);
+    assert_eq!(
+        g2p.g2p_nbest("AB", 1).unwrap(),
+        vec![g2p.g2p("AB").unwrap()]
+    );
+}
+
+#[test]
+fn beam() {
+    let nbest = |beam| {
+        let config = G2PConfig {
+            beam: Some(beam),
+            ..Default::default()
+        };
+        let g2p = G2P::new(config, variants_model()).unwrap();
+        g2p.g2p_nbest("AB", 10)
+            .unwrap()
+            .into_iter()
+            .map(|(_, score)| score)
+            .collect::<Vec<f32>>()
+    };
+    assert_eq!(nbest(0.4), vec![1.5]);
+    // The cutoff is inclusive
+    assert_eq!(nbest(0.5), vec![1.5, 2.0]);
+    assert_eq!(nbest(2.5), vec![1.5, 2.0, 4.0, 4.0]);
+}
+
+#[test]
+fn pmass() {
+    // Shares of the n-best list are 0.565, 0.342, 0.046 and 0.046
+    let nbest = |pmass| {
+        let config = G2PConfig {
+            pmass: Some(pmass),
+            ..Default::default()
+        };
+        let g2p = G2P::new(config, variants_model()).unwrap();
+        g2p.g2p_nbest("AB", 10).unwrap().len()
+    };
+    assert_eq!(nbest(0.5), 1);
+    assert_eq!(nbest(0.9), 2);
+    assert_eq!(nbest(0.95), 3);
+    assert_eq!(nbest(1.0), 4);
+}
+
+/// For phrases, the cutoffs apply to the combined n-best list
+#[test]
+fn phrase_cutoffs() {
+    let config = G2PConfig {
+        beam: Some(2.0),
+        delimiters: " ".to_owned(),
+        ..Default::default()
+    };
+    let g2p = G2P::new(config, variants_model()).unwrap();
+    assert_eq!(
+        g2p.g2p_nbest("A A", 10).unwrap(),
+        vec![
+            (vec!["EY", "EY"], 2.0),
+            (vec!["AH", "EY"], 4.0),
+            (vec!["EY", "AH"], 4.0),
+        ]
+    );
+    // Shares are 0.776, 0.105, 0.105 and 0.014
+    let config = G2PConfig {
+        pmass: Some(0.7),
+        delimiters: " ".to_owned(),
+        ..Default::default()
+    };
+    let g2p = G2P::new(config, variants_model()).unwrap();
+    assert_eq!(
+        g2p.g2p_nbest("A A", 10).unwrap(),
+        vec![(vec!["EY", "EY"], 2.0)]
+    );
+}
+
+fn oov_g2p(oov: OovPolicy) -> G2P {
+    let config = G2PConfig {
+        oov,
+        ..Default::default()
+    };
+    G2P::new(config, variants_model()).unwrap()
+}
+
+#[test]
+fn oov_fail() {
+    let g2p = oov_g2p(OovPolicy::Fail);
+    let err = g2p.g2p("AXB").unwrap_err();
+    assert_eq!(err.to_string(), "Input symbol X not found");
+    assert!(g2p.g2p_nbest("AXB", 2).is_err());
+}
+
+#[test]
+fn oov_skip() {
+    let g2p = oov_g2p(OovPolicy::Skip);
+    assert_eq!(g2p.g2p("AXB").unwrap(), (vec!["EY", "B"], 1.5));
+    assert_eq!(
+        g2p.g2p_nbest("AXB", 2).unwrap(),
+        vec![(vec!["EY", "B"], 1.5), (vec!["EY"], 2.0)]
+    );
+    // Nothing left to pronounce
+    assert_eq!(g2p.g2p("XX").unwrap(), (vec![], 0.0));
+}
+
+#[test]
+fn oov_empty() {
+    let g2p = oov_g2p(OovPolicy::Empty);
+    assert_eq!(g2p.g2p("AXB").unwrap(), (vec![], f32::INFINITY));
+    assert_eq!(
+        g2p.g2p_nbest("AXB", 2).unwrap(),
+        vec![(vec![], f32::INFINITY)]
+    );
+    assert_eq!(g2p.g2p("AB").unwrap(), (vec!["EY", "B"], 1.5));
+}
+
+/// Unknown graphemes are replaced by zero or more known ones, and the
+/// policy applies to whatever is still unknown
+#[test]
+fn substitutions() {
+    let file = NamedTempFile::new().unwrap();
+    fs::write(file.path(), "\u{c1}\tA\n\u{c7}\tB\n'\t\n\u{c6}\tAB\nX\tY\n").unwrap();
+    let mut g2p = oov_g2p(OovPolicy::Fail);
+    g2p.load_substitutions(&file.path().to_path_buf()).unwrap();
+    assert_eq!(g2p.g2p("\u{c1}\u{c7}").unwrap(), (vec!["EY", "B"], 1.5));
+    // Replaced by nothing
+    assert_eq!(g2p.g2p("A'B").unwrap(), (vec!["EY", "B"], 1.5));
+    // Replaced by several graphemes
+    assert_eq!(g2p.g2p("\u{c6}").unwrap(), (vec!["EY", "B"], 1.5));
+    // Known graphemes are not replaced
+    assert_eq!(g2p.g2p("A").unwrap(), (vec!["EY"], 1.0));
+    let err = g2p.g2p("AX").unwrap_err();
+    assert_eq!(err.to_string(), "Input symbol Y not found");
+}
+
+/// Output with several threads is the same as without, in the order
+/// of the input, over enough words for several blocks
+#[test]
+fn parallel_jobs() {
+    let dir = TempDir::new().unwrap();
+    let dir = dir.path();
+    let model = dir.join("model.fst");
+    variants_model().write(&model).unwrap();
+    // All words of A, B and X up to 7 graphemes, some of which fail
+    let mut words = vec![String::new()];
+    let mut start = 0;
+    for _ in 0..7 {
+        let end = words.len();
+        for i in start..end {
+            for g in ["A", "B", "X"] {
+                words.push(format!("{}{}", words[i], g));
+            }
+        }
+        start = end;
+    }
+    let words = &words[1..];
+    let input = dir.join("words");
+    fs::write(&input, words.join("\n") + "\n").unwrap();
+    let run = |jobs: usize| {
+        let errors = dir.join(format!("errors.{}", jobs));
+        let output = Command::new(env!("CARGO_BIN_EXE_rustfst-g2p"))
+            .arg("g2p")
+            .arg(&model)
+            .arg(&input)
+            .args(["--nbest", "2", "--jobs", &jobs.to_string()])
+            .arg("--errors")
+            .arg(&errors)
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        (stdout, fs::read_to_string(&errors).unwrap())
+    };
+    let (serial, serial_errors) = run(1);
+    let (parallel, parallel_errors) = run(4);
+    assert_eq!(parallel, serial);
+    assert_eq!(parallel_errors, serial_errors);
+    // Words with X fail, and the rest are output, both in order
+    let first_column = |text: &str| {
+        let mut column: Vec<String> = text
+            .lines()
+            .map(|line| line.split('\t').next().unwrap().to_owned())
+            .collect();
+        column.dedup();
+        column
+    };
+    let (failed, decoded): (Vec<String>, Vec<String>) =
+        words.iter().cloned().partition(|word| word.contains('X'));
+    assert_eq!(first_column(&serial), decoded);
+    assert_eq!(first_column(&serial_errors), failed);
+}
+
+/// Input that cannot be read stops decoding with an error, rather
+/// than being reported as a failed word
+#[test]
+fn unreadable_input() {
+    let dir = TempDir::new().unwrap();
+    let model = dir.path().join("model.fst");
+    variants_model().write(&model).unwrap();
+    let input = dir.path().join("words");
+    fs::write(&input, b"AB\n\xff\nA\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_rustfst-g2p"))
+        .arg("g2p")
+        .arg(&model)
+        .arg(&input)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("valid UTF-8"), "{}", stderr);
+    assert!(!stderr.contains("words failed"), "{}", stderr);
+}
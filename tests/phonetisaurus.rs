@@ -0,0 +1,81 @@
+//! Compatibility with models from Phonetisaurus
+
+mod common;
+
+use common::tiny_fst;
+use rustfst::prelude::*;
+use rustfst_g2p::g2p::{Config as G2PConfig, G2P};
+use rustfst_g2p::model::Model;
+use std::fs;
+use std::path::PathBuf;
+use tempfile::NamedTempFile;
+
+fn testdata(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(name)
+}
+
+/// A tiny model with the symbol table layout of
+/// `phonetisaurus-arpa2wfst`: the tie at label 1, the skip token as an
+/// ordinary symbol on both sides, and clusters after that
+fn layout_model() -> VectorFst<TropicalWeight> {
+    tiny_fst(
+        &["|", "_", "A", "B", "A|B"],
+        &["|", "_", "EY", "AH", "B"],
+        &[
+            // A -> EY, then insert AH, which is cheaper than stopping
+            (0, "A", "EY", 1.0, 1),
+            (1, "_", "AH", 0.5, 2),
+            // A|B -> B
+            (0, "A|B", "B", 2.0, 2),
+        ],
+        &[(1, 3.0), (2, 0.0)],
+    )
+}
+
+/// Phone insertions on the skip token are decoded, and clusters are
+/// found with the tie at label 1
+#[test]
+fn symbol_layout() {
+    // Go through a bare OpenFST binary like Phonetisaurus writes
+    let file = NamedTempFile::new().unwrap();
+    let path = file.path().to_path_buf();
+    layout_model().write(&path).unwrap();
+    let model = Model::read(&path).unwrap();
+    assert!(model.metadata.is_none());
+    let g2p = G2P::new(G2PConfig::default(), model).unwrap();
+    assert_eq!(g2p.g2p("A").unwrap(), (vec!["EY", "AH"], 1.5));
+    assert_eq!(g2p.g2p("AB").unwrap(), (vec!["B"], 2.0));
+}
+
+/// A small model from `phonetisaurus-arpa2wfst` gives the same 1-best
+/// pronunciations and scores as `phonetisaurus-g2pfst` for every word
+/// of the test sample
+#[test]
+#[ignore = "testdata/p11s is not checked in yet (see make_phonetisaurus_testdata.sh)"]
+fn phonetisaurus_g2pfst() {
+    let model = Model::read(&testdata("testdata/p11s/model.fst")).unwrap();
+    let g2p = G2P::new(G2PConfig::default(), model).unwrap();
+    let refs = fs::read_to_string(testdata("testdata/librispeech.test.sample")).unwrap();
+    let hyps = fs::read_to_string(testdata("testdata/p11s/test.hyp")).unwrap();
+    let words: Vec<&str> = refs
+        .lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .collect();
+    let hyps: Vec<&str> = hyps.lines().collect();
+    assert_eq!(hyps.len(), words.len(), "One hypothesis per word");
+    for (word, line) in words.into_iter().zip(hyps) {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let (hyp_word, score, pron) = (fields[0], fields[1], fields[2]);
+        assert_eq!(hyp_word, word);
+        let score: f32 = score.parse().unwrap();
+        let (phones, actual) = g2p.g2p(word).unwrap();
+        assert_eq!(phones.join(" "), pron, "Pronunciation of {}", word);
+        assert!(
+            (actual - score).abs() < 1e-3,
+            "Score of {}: {} != {}",
+            word,
+            actual,
+            score
+        );
+    }
+}
@@ -0,0 +1,110 @@
+//! Pronunciation of phrases word by word and from an exception lexicon
+
+mod common;
+
+use common::tiny_fst;
+use rustfst_g2p::g2p::{Config as G2PConfig, Source, G2P};
+use rustfst_g2p::model::Model;
+use std::fs;
+use tempfile::NamedTempFile;
+
+/// A tiny model reading A as EY or AH and B as B
+fn letters_model() -> Model {
+    let fst = tiny_fst(
+        &["|", "A", "B"],
+        &["|", "EY", "AH", "B"],
+        &[
+            (0, "A", "EY", 1.0, 0),
+            (0, "A", "AH", 2.0, 0),
+            (0, "B", "B", 1.0, 0),
+        ],
+        &[(0, 0.0)],
+    );
+    Model::from(fst)
+}
+
+fn g2p_config() -> G2PConfig {
+    G2PConfig {
+        delimiters: " -".to_owned(),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn split_words() {
+    let g2p = G2P::new(g2p_config(), letters_model()).unwrap();
+    assert_eq!(g2p.g2p("A B").unwrap(), (vec!["EY", "B"], 2.0));
+    assert_eq!(g2p.g2p(" AB-A ").unwrap(), (vec!["EY", "B", "EY"], 3.0));
+    assert_eq!(
+        g2p.g2p_nbest("A-A", 3).unwrap(),
+        vec![
+            (vec!["EY", "EY"], 2.0),
+            (vec!["AH", "EY"], 3.0),
+            (vec!["EY", "AH"], 3.0)
+        ]
+    );
+    let config = G2PConfig {
+        delimiters: String::new(),
+        ..g2p_config()
+    };
+    let g2p = G2P::new(config, letters_model()).unwrap();
+    assert!(g2p.g2p("A B").is_err());
+}
+
+#[test]
+fn stray_delimiters() {
+    let g2p = G2P::new(g2p_config(), letters_model()).unwrap();
+    assert_eq!(g2p.g2p("AB-").unwrap(), (vec!["EY", "B"], 2.0));
+    assert_eq!(g2p.g2p("-A").unwrap(), (vec!["EY"], 1.0));
+    assert_eq!(g2p.g2p_nbest(" B ", 2).unwrap(), vec![(vec!["B"], 1.0)]);
+}
+
+#[test]
+fn phrase_lexicon() {
+    let file = NamedTempFile::new().unwrap();
+    fs::write(file.path(), "A B\tB EY\nA B\tB AH\nB\tAH B\n").unwrap();
+    let mut g2p = G2P::new(g2p_config(), letters_model()).unwrap();
+    g2p.load_lexicon(&file.path().to_path_buf()).unwrap();
+    assert_eq!(g2p.g2p("A B").unwrap(), (vec!["B", "EY"], 0.0));
+    assert_eq!(g2p.source("A B"), Source::Lexicon);
+    assert_eq!(
+        g2p.g2p_nbest("A B", 3).unwrap(),
+        vec![(vec!["B", "EY"], 0.0), (vec!["B", "AH"], 0.0)]
+    );
+    // Words not in the lexicon fall back to the model
+    assert_eq!(g2p.g2p("B").unwrap(), (vec!["AH", "B"], 0.0));
+    assert_eq!(g2p.source("B"), Source::Lexicon);
+    assert_eq!(g2p.g2p("AB").unwrap(), (vec!["EY", "B"], 2.0));
+    assert_eq!(g2p.source("AB"), Source::Model);
+    assert_eq!(g2p.g2p("A-B").unwrap(), (vec!["EY", "AH", "B"], 1.0));
+    assert_eq!(g2p.source("A-B"), Source::Mixed);
+    assert_eq!(
+        g2p.g2p_nbest("A-B", 3).unwrap(),
+        vec![(vec!["EY", "AH", "B"], 1.0), (vec!["AH", "AH", "B"], 2.0)]
+    );
+}
+
+/// Lexicon pronunciations go through the same cutoffs as decoded ones
+#[test]
+fn lexicon_cutoffs() {
+    let file = NamedTempFile::new().unwrap();
+    fs::write(file.path(), "A B\tB EY\nA B\tB AH\nA B\tEY B\n").unwrap();
+    let config = G2PConfig {
+        pmass: Some(0.5),
+        ..g2p_config()
+    };
+    let mut g2p = G2P::new(config, letters_model()).unwrap();
+    g2p.load_lexicon(&file.path().to_path_buf()).unwrap();
+    // Each has a third of the probability mass
+    assert_eq!(
+        g2p.g2p_nbest("A B", 3).unwrap(),
+        vec![(vec!["B", "EY"], 0.0), (vec!["B", "AH"], 0.0)]
+    );
+    let config = G2PConfig {
+        beam: Some(0.0),
+        ..g2p_config()
+    };
+    let mut g2p = G2P::new(config, letters_model()).unwrap();
+    g2p.load_lexicon(&file.path().to_path_buf()).unwrap();
+    assert_eq!(g2p.g2p_nbest("A B", 3).unwrap().len(), 3);
+}
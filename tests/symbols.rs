@@ -0,0 +1,51 @@
+//! Resolution of separator symbols by name
+
+mod common;
+
+use common::tiny_fst;
+use rustfst_g2p::g2p::{Config as G2PConfig, G2P};
+use rustfst_g2p::model::{Metadata, Model};
+
+/// A tiny model whose separators come last in the symbol tables
+fn reordered_model() -> Model {
+    let fst = tiny_fst(
+        &["A", "B", "A|B", "|"],
+        &["EY", "B", "|"],
+        &[(0, "A", "EY", 1.0, 1), (0, "A|B", "B", 2.0, 1)],
+        &[(1, 0.0)],
+    );
+    Model {
+        metadata: Some(Metadata {
+            gsep: None,
+            seq1_sep: "|".to_owned(),
+            seq2_sep: "|".to_owned(),
+            s1s2_sep: "}".to_owned(),
+            skip: "_".to_owned(),
+            seq1_max: None,
+            seq2_max: None,
+            seq1_del: None,
+            seq2_del: None,
+            order: None,
+            method: None,
+            fractional: None,
+            data_hash: None,
+        }),
+        fst,
+    }
+}
+
+#[test]
+fn separators_by_name() {
+    let g2p = G2P::new(G2PConfig::default(), reordered_model()).unwrap();
+    assert_eq!(g2p.g2p("A").unwrap(), (vec!["EY"], 1.0));
+    assert_eq!(g2p.g2p("AB").unwrap(), (vec!["B"], 2.0));
+}
+
+#[test]
+fn missing_separator() {
+    let config = G2PConfig {
+        seq1_sep: Some("+".to_owned()),
+        ..Default::default()
+    };
+    assert!(G2P::new(config, reordered_model()).is_err());
+}
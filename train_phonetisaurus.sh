@@ -13,4 +13,4 @@ phonetisaurus-arpa2wfst --lm=$TMPDIR/train.arpa --ofile=$TMPDIR/model.fst
 fstprint $TMPDIR/model.fst > $TMPDIR/model.fst.txt
 cut -d' ' -f1 testdata/librispeech.test.sample > $TMPDIR/test.words
 phonetisaurus-g2pfst --model=$TMPDIR/model.fst --wordlist=$TMPDIR/test.words > $TMPDIR/test.hyp
-python calculateER.py --hyp $TMPDIR/test.hyp --ref testdata/librispeech.test.sample 
+cargo run --release evaluate --hyp $TMPDIR/test.hyp testdata/librispeech.test.sample
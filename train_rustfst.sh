@@ -10,4 +10,4 @@ cargo run --release train --order=5 --write-fsts \
       $TMPDIR/train.aligned $TMPDIR/model.fst
 cut -d' ' -f1 testdata/librispeech.test.sample > $TMPDIR/test.words
 cargo run --release g2p $TMPDIR/model.fst $TMPDIR/test.words > $TMPDIR/test.hyp
-python calculateER.py --hyp $TMPDIR/test.hyp --ref testdata/librispeech.test.sample 
+cargo run --release evaluate --hyp $TMPDIR/test.hyp testdata/librispeech.test.sample